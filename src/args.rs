@@ -8,12 +8,18 @@ use std::ffi::OsString;
 use std::iter::IntoIterator;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
-use clap::{self, AppSettings, Arg, ArgMatches};
+use clap::{self, AppSettings, Arg, ArgMatches, SubCommand};
 use conv::TryFrom;
 use conv::errors::NoError;
+use glob::Pattern;
 use semver::{Version, VersionReq, ReqParseError, SemVerError};
 
+use deps::{DepKinds, Features};
+use extract::Filter;
+use index::VersionSource;
+use registry::is_valid_crate_name;
 use super::{NAME, VERSION};
 
 
@@ -36,7 +42,7 @@ pub fn parse_from_argv<I, T>(argv: I) -> Result<Options, ArgsError>
     }
 
     let parser = create_parser();
-    let matches = try!(parser.get_matches_from_safe(argv));
+    let matches = parser.get_matches_from_safe(argv)?;
     Options::try_from(matches)
 }
 
@@ -49,10 +55,67 @@ pub struct Options {
     /// Corresponds to the number of times the -v flag has been passed.
     /// If -q has been used instead, this will be negative.
     pub verbosity: isize,
-    /// Crate to download.
-    pub crate_: Crate,
+    /// Cache management command to run instead of downloading anything.
+    pub cache_command: Option<CacheCommand>,
+    /// Crates to download.
+    pub crates: Vec<Crate>,
+    /// Cargo.lock file whose registry packages should be downloaded.
+    pub lockfile: Option<PathBuf>,
+    /// Whether to download all the dependencies of the crates, recursively.
+    pub recursive: bool,
+    /// Kinds of dependencies to follow, besides the normal ones.
+    pub dep_kinds: DepKinds,
+    /// Features to enable on the crates when resolving their dependencies.
+    pub features: Features,
+    /// Maximum number of crates to download concurrently.
+    pub jobs: usize,
+    /// Name of the registry to download from, as defined in Cargo config.
+    /// If omitted, crates.io is used.
+    pub registry: Option<String>,
+    /// File with the token to authenticate with to the registry, overriding Cargo credentials.
+    pub token_file: Option<PathBuf>,
+    /// Where to look up the available versions of the crate,
+    /// if explicitly specified.
+    pub versions_from: Option<VersionSource>,
+    /// Whether yanked versions should be considered when matching version requirements.
+    pub allow_yanked: bool,
+    /// Whether pre-release versions should be considered for any version requirement.
+    pub allow_prerelease: bool,
+    /// Expected SHA256 checksum of the crate's archive,
+    /// overriding the one from the registry.
+    pub checksum: Option<String>,
+    /// Whether to verify the checksum of the downloaded archive.
+    pub verify: bool,
+    /// Whether to only list the available versions of the crate
+    /// rather than downloading it.
+    pub list: bool,
     /// Whether to extract the crate's archive.
     pub extract: bool,
+    /// Whether to overwrite an existing directory when extracting the crate.
+    pub force: bool,
+    /// Which files to extract from the crate's archive, and where.
+    pub filter: Filter,
+    /// Path of a single file within the crate to print instead of the archive.
+    pub cat: Option<PathBuf>,
+    /// Whether to list the files in the crate's archive instead of outputting it.
+    pub list_files: bool,
+    /// Format of the listing printed by --list-files.
+    pub format: Format,
+    /// Format of the messages about downloaded crates.
+    /// With JSON, they are printed to stdout (and archives never are).
+    pub message_format: Format,
+    /// Directory of the download cache, if explicitly specified.
+    pub cache_dir: Option<PathBuf>,
+    /// Whether to bypass the download cache (and Cargo's).
+    pub no_cache: bool,
+    /// Whether to never access the network.
+    pub offline: bool,
+    /// How many times to retry failed network requests.
+    pub retries: u32,
+    /// HTTP proxy to use, overriding Cargo config.
+    pub proxy: Option<String>,
+    /// File with certificate authorities to trust, overriding Cargo config.
+    pub cacert: Option<PathBuf>,
     /// Where to output the crate's archive.
     pub output: Option<Output>,
 }
@@ -63,31 +126,190 @@ impl Options {
     pub fn verbose(&self) -> bool { self.verbosity > 0 }
     #[inline]
     pub fn quiet(&self) -> bool { self.verbosity < 0 }
+
+    /// Whether more than one crate may be downloaded,
+    /// in which case the output is a directory.
+    #[inline]
+    pub fn multiple(&self) -> bool {
+        self.crates.len() > 1 || self.lockfile.is_some() || self.recursive
+    }
 }
 
 impl<'a> TryFrom<ArgMatches<'a>> for Options {
     type Err = ArgsError;
 
     fn try_from(matches: ArgMatches<'a>) -> Result<Self, Self::Err> {
-        let verbose_count = matches.occurrences_of(OPT_VERBOSE) as isize;
-        let quiet_count = matches.occurrences_of(OPT_QUIET) as isize;
-        let verbosity = verbose_count - quiet_count;
+        // Global flags can also be passed after subcommands.
+        let subcommand = matches.subcommand_matches(CMD_CACHE);
+        let cache_subcommand = subcommand.and_then(|m| m.subcommand_name()
+            .and_then(|name| m.subcommand_matches(name)));
+        let all_matches: Vec<_> = Some(&matches).into_iter()
+            .chain(subcommand).chain(cache_subcommand).collect();
+        let occurrences = |arg| all_matches.iter().map(|m| m.occurrences_of(arg) as isize).max().unwrap_or(0);
+        let verbosity = occurrences(OPT_VERBOSE) - occurrences(OPT_QUIET);
+        let cache_dir = all_matches.iter().rev()
+            .find_map(|m| m.value_of(OPT_CACHE_DIR)).map(PathBuf::from);
+        let no_cache = matches.is_present(OPT_NO_CACHE);
+        let offline = matches.is_present(OPT_OFFLINE);
 
-        let crate_ = Crate::from_str(matches.value_of(ARG_CRATE).unwrap())?;
+        let cache_command = match subcommand.map(|m| m.subcommand()) {
+            Some((CMD_CACHE_LIST, _)) => Some(CacheCommand::List),
+            Some((CMD_CACHE_PRUNE, Some(m))) => Some(CacheCommand::Prune{
+                older_than: match m.value_of(OPT_OLDER_THAN) {
+                    Some(age) => Some(parse_duration(age)
+                        .ok_or_else(|| ArgsError::Duration(age.to_owned()))?),
+                    None => None,
+                },
+            }),
+            _ => None,
+        };
+
+        let crates = matches.values_of(ARG_CRATE).into_iter().flatten()
+            .map(Crate::from_str).collect::<Result<Vec<_>, _>>()?;
+        let lockfile = matches.value_of(OPT_LOCKFILE).map(PathBuf::from);
+        let recursive = matches.is_present(OPT_RECURSIVE);
+        let dep_kinds = DepKinds{
+            build: matches.is_present(OPT_BUILD_DEPS),
+            dev: matches.is_present(OPT_DEV_DEPS),
+        };
+        let features = Features{
+            // Like in Cargo, features can be separated by commas or spaces.
+            features: matches.values_of(OPT_FEATURES).into_iter().flatten()
+                .flat_map(|f| f.split(|c: char| c == ',' || c.is_whitespace()))
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_owned())
+                .collect(),
+            all: matches.is_present(OPT_ALL_FEATURES),
+            default: !matches.is_present(OPT_NO_DEFAULT_FEATURES),
+        };
+        let jobs = match matches.value_of(OPT_JOBS) {
+            Some(j) => match j.parse() {
+                Ok(n) if n > 0 => n,
+                _ => return Err(ArgsError::Jobs(j.to_owned())),
+            },
+            None => DEFAULT_JOBS,
+        };
+        let registry = matches.value_of(OPT_REGISTRY).map(|r| r.to_owned());
+        let token_file = matches.value_of(OPT_TOKEN_FILE).map(PathBuf::from);
+        let versions_from = matches.value_of(OPT_VERSIONS_FROM)
+            .map(|v| v.parse().unwrap());  // value already validated by clap
+        let allow_yanked = matches.is_present(OPT_ALLOW_YANKED);
+        let allow_prerelease = matches.is_present(OPT_ALLOW_PRERELEASE);
+        let checksum = match matches.value_of(OPT_CHECKSUM) {
+            Some(c) => {
+                let valid = c.len() == 64 && c.chars().all(|c| c.is_ascii_hexdigit());
+                if !valid {
+                    return Err(ArgsError::Checksum(c.to_owned()));
+                }
+                Some(c.to_lowercase())
+            }
+            None => None,
+        };
+        let verify = !matches.is_present(OPT_NO_VERIFY);
+        let list = matches.is_present(OPT_LIST);
         let extract = matches.is_present(OPT_EXTRACT);
+        let force = matches.is_present(OPT_FORCE);
+        let filter = Filter{
+            strip_components: match matches.value_of(OPT_STRIP_COMPONENTS) {
+                Some(n) => n.parse()
+                    .map_err(|_| ArgsError::StripComponents(n.to_owned()))?,
+                None => 0,
+            },
+            include: parse_globs(matches.values_of(OPT_INCLUDE))?,
+            exclude: parse_globs(matches.values_of(OPT_EXCLUDE))?,
+        };
+        let cat = matches.value_of(OPT_CAT).map(PathBuf::from);
+        let list_files = matches.is_present(OPT_LIST_FILES);
+        let format = matches.value_of(OPT_FORMAT)
+            .map(|f| f.parse().unwrap())  // value already validated by clap
+            .unwrap_or_default();
+        let message_format = matches.value_of(OPT_MESSAGE_FORMAT)
+            .map(|f| f.parse().unwrap())  // value already validated by clap
+            .unwrap_or_default();
+        let retries = match matches.value_of(OPT_RETRIES) {
+            Some(r) => r.parse().map_err(|_| ArgsError::Retries(r.to_owned()))?,
+            None => DEFAULT_RETRIES,
+        };
+        let proxy = matches.value_of(OPT_PROXY).map(|p| p.to_owned());
+        let cacert = matches.value_of(OPT_CACERT).map(PathBuf::from);
         let output = matches.value_of(OPT_OUTPUT).map(Output::from);
 
-        // TODO: sanity check Output::Path that it doesn't exist,
-        // because fs::rename behaves oddly (i.e. fails) on Windows
-        // if a directory target exists
         if extract && output == Some(Output::Stdout) {
             return Err(ArgsError::CantExtractToStdout);
         }
-
-        Ok(Options{verbosity, crate_, extract, output})
+        if message_format == Format::Json && output == Some(Output::Stdout) {
+            return Err(ArgsError::JsonToStdout);
+        }
+        let opts = Options{
+            verbosity,
+            cache_command,
+            crates,
+            lockfile,
+            recursive,
+            dep_kinds,
+            features,
+            jobs,
+            registry,
+            token_file,
+            versions_from,
+            allow_yanked,
+            allow_prerelease,
+            checksum,
+            verify,
+            list,
+            extract,
+            force,
+            filter,
+            cat,
+            list_files,
+            format,
+            message_format,
+            cache_dir,
+            no_cache,
+            offline,
+            retries,
+            proxy,
+            cacert,
+            output,
+        };
+        if opts.multiple() {
+            if opts.checksum.is_some() {
+                return Err(ArgsError::AmbiguousChecksum);
+            }
+            if opts.output == Some(Output::Stdout) || opts.cat.is_some() || opts.list_files {
+                return Err(ArgsError::MultipleToStdout);
+            }
+        }
+        Ok(opts)
     }
 }
 
+/// Parse a duration like "30d", "12h", "15m", or "2w".
+/// Without a unit, the number is in days.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "d"),
+    };
+    let secs: u64 = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(secs).map(Duration::from_secs)
+}
+
+/// Parse the glob patterns passed to --include or --exclude.
+fn parse_globs<'a, I: IntoIterator<Item=&'a str>>(globs: Option<I>) -> Result<Vec<Pattern>, ArgsError> {
+    globs.into_iter().flatten()
+        .map(|g| Pattern::new(g).map_err(|e| ArgsError::Glob(g.to_owned(), e.msg)))
+        .collect()
+}
+
 
 /// Specification of a crate to download.
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -101,17 +323,14 @@ impl FromStr for Crate {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts: Vec<_> = s.splitn(2, "=").map(|p| p.trim()).collect();
         let name = parts[0].to_owned();
+        if !is_valid_crate_name(&name) {
+            return Err(CrateError::Name(name));
+        }
         if parts.len() < 2 {
-            let valid_name =
-                name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_');
-            if valid_name {
-                Ok(Crate{
-                    name,
-                    version: CrateVersion::Other(VersionReq::any()),
-                })
-            } else {
-                Err(CrateError::Name(name))
-            }
+            Ok(Crate{
+                name,
+                version: CrateVersion::Other(VersionReq::any()),
+            })
         } else {
             let version = CrateVersion::from_str(parts[1])?;
             Ok(Crate{name, version})
@@ -119,6 +338,11 @@ impl FromStr for Crate {
     }
 }
 impl Crate {
+    /// Specification of the exact version of given crate.
+    pub fn exact(name: &str, version: Version) -> Self {
+        Crate{name: name.to_owned(), version: CrateVersion::Exact(version)}
+    }
+
     #[inline]
     pub fn name(&self) -> &str {
         &self.name
@@ -131,7 +355,7 @@ impl Crate {
         }
     }
 
-    pub fn version_requirement(&self) -> Cow<VersionReq> {
+    pub fn version_requirement(&self) -> Cow<'_, VersionReq> {
         match self.version {
             CrateVersion::Exact(ref v) => Cow::Owned(VersionReq::exact(v)),
             CrateVersion::Other(ref r) => Cow::Borrowed(r),
@@ -156,8 +380,8 @@ impl FromStr for CrateVersion {
     type Err = CrateVersionError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.starts_with("=") {
-            let version = Version::from_str(&s[1..])?;
+        if let Some(s) = s.strip_prefix('=') {
+            let version = Version::from_str(s)?;
             Ok(CrateVersion::Exact(version))
         } else {
             let version_req = VersionReq::from_str(s)?;
@@ -167,9 +391,40 @@ impl FromStr for CrateVersion {
 }
 impl fmt::Display for CrateVersion {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &CrateVersion::Exact(ref v) => write!(fmt, "={}", v),
-            &CrateVersion::Other(ref r) => write!(fmt, "{}", r),
+        match *self {
+            CrateVersion::Exact(ref v) => write!(fmt, "={}", v),
+            CrateVersion::Other(ref r) => write!(fmt, "{}", r),
+        }
+    }
+}
+
+/// Command for managing the download cache.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheCommand {
+    /// List the cached crate archives.
+    List,
+    /// Remove the cached crate archives that haven't been used for given time
+    /// (or all of them).
+    Prune{older_than: Option<Duration>},
+}
+
+/// Format of listings printed by the program.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Table meant for humans.
+    #[default]
+    Human,
+    /// JSON meant for other programs.
+    Json,
+}
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format `{}`", s)),
         }
     }
 }
@@ -200,9 +455,9 @@ impl FromStr for Output {
 }
 impl fmt::Display for Output {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &Output::Path(ref p) => write!(fmt, "{}", p.display()),
-            &Output::Stdout => write!(fmt, "-"),
+        match *self {
+            Output::Path(ref p) => write!(fmt, "{}", p.display()),
+            Output::Stdout => write!(fmt, "-"),
         }
     }
 }
@@ -215,8 +470,26 @@ pub enum ArgsError {
     Parse(clap::Error),
     /// Error when parsing crate version.
     Crate(CrateError),
+    /// Invalid number of concurrent downloads passed to --jobs.
+    Jobs(String),
+    /// Invalid SHA256 checksum passed to --checksum.
+    Checksum(String),
+    /// Invalid number of retries passed to --retries.
+    Retries(String),
+    /// Invalid number of path components passed to --strip-components.
+    StripComponents(String),
+    /// Invalid glob pattern passed to --include or --exclude.
+    Glob(String, &'static str),
+    /// Invalid duration passed to --older-than.
+    Duration(String),
+    /// Cannot pass --checksum when downloading more than one crate.
+    AmbiguousChecksum,
     /// Cannot pass -x alpng with an explicit --output "-" (stdout).
     CantExtractToStdout,
+    /// Cannot output more than one crate to stdout.
+    MultipleToStdout,
+    /// Cannot output the crate to stdout when it's used for JSON messages.
+    JsonToStdout,
 }
 impl From<clap::Error> for ArgsError {
     fn from(input: clap::Error) -> Self {
@@ -230,21 +503,35 @@ impl From<CrateError> for ArgsError {
 }
 impl Error for ArgsError {
     fn description(&self) -> &str { "failed to parse argv" }
-    fn cause(&self) -> Option<&Error> {
-        match self {
-            &ArgsError::Parse(ref e) => Some(e),
-            &ArgsError::Crate(ref e) => Some(e),
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            ArgsError::Parse(ref e) => Some(e),
+            ArgsError::Crate(ref e) => Some(e),
             _ => None,
         }
     }
 }
 impl fmt::Display for ArgsError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &ArgsError::Parse(ref e) => write!(fmt, "parse error: {}", e),
-            &ArgsError::Crate(ref e) => write!(fmt, "invalid crate spec: {}", e),
-            &ArgsError::CantExtractToStdout =>
+        match *self {
+            ArgsError::Parse(ref e) => write!(fmt, "parse error: {}", e),
+            ArgsError::Crate(ref e) => write!(fmt, "invalid crate spec: {}", e),
+            ArgsError::Jobs(ref j) => write!(fmt, "invalid number of jobs `{}`", j),
+            ArgsError::Checksum(ref c) => write!(fmt, "invalid SHA256 checksum `{}`", c),
+            ArgsError::Retries(ref r) => write!(fmt, "invalid number of retries `{}`", r),
+            ArgsError::StripComponents(ref n) =>
+                write!(fmt, "invalid number of path components to strip `{}`", n),
+            ArgsError::Glob(ref g, msg) => write!(fmt, "invalid glob pattern `{}`: {}", g, msg),
+            ArgsError::Duration(ref d) => write!(fmt, "invalid duration `{}`", d),
+            ArgsError::AmbiguousChecksum =>
+                write!(fmt, "--checksum can only be used when downloading a single crate"),
+            ArgsError::CantExtractToStdout =>
                 write!(fmt, "cannot extract a crate to standard output"),
+            ArgsError::MultipleToStdout =>
+                write!(fmt, "cannot output more than one crate to standard output"),
+            ArgsError::JsonToStdout => write!(fmt, concat!(
+                "cannot output the crate to standard output ",
+                "with --message-format json")),
         }
     }
 }
@@ -264,18 +551,18 @@ impl From<CrateVersionError> for CrateError {
 }
 impl Error for CrateError {
     fn description(&self) -> &str { "invalid crate specification" }
-    fn cause(&self) -> Option<&Error> {
-        match self {
-            &CrateError::Version(ref e) => Some(e),
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            CrateError::Version(ref e) => Some(e),
             _ => None,
         }
     }
 }
 impl fmt::Display for CrateError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &CrateError::Name(ref n) => write!(fmt, "invalid crate name `{}`", n),
-            &CrateError::Version(ref e) => write!(fmt, "invalid crate version: {}", e),
+        match *self {
+            CrateError::Name(ref n) => write!(fmt, "invalid crate name `{}`", n),
+            CrateError::Version(ref e) => write!(fmt, "invalid crate version: {}", e),
         }
     }
 }
@@ -295,15 +582,55 @@ pub enum CrateVersionError {
 type Parser<'p> = clap::App<'p, 'p>;
 
 
+/// Default number of crates to download concurrently.
+const DEFAULT_JOBS: usize = 4;
+/// Default number of retries of a failed network request.
+const DEFAULT_RETRIES: u32 = 3;
+
 lazy_static! {
     static ref ABOUT: &'static str = option_env!("CARGO_PKG_DESCRIPTION").unwrap_or("");
 }
 
-const ARG_CRATE: &'static str = "crate";
-const OPT_EXTRACT: &'static str = "extract";
-const OPT_OUTPUT: &'static str = "output";
-const OPT_VERBOSE: &'static str = "verbose";
-const OPT_QUIET: &'static str = "quiet";
+const CMD_CACHE: &str = "cache";
+const CMD_CACHE_LIST: &str = "list";
+const CMD_CACHE_PRUNE: &str = "prune";
+const OPT_OLDER_THAN: &str = "older-than";
+
+const ARG_CRATE: &str = "crate";
+const OPT_LOCKFILE: &str = "lockfile";
+const OPT_RECURSIVE: &str = "recursive";
+const OPT_BUILD_DEPS: &str = "build-deps";
+const OPT_DEV_DEPS: &str = "dev-deps";
+const OPT_FEATURES: &str = "features";
+const OPT_ALL_FEATURES: &str = "all-features";
+const OPT_NO_DEFAULT_FEATURES: &str = "no-default-features";
+const OPT_JOBS: &str = "jobs";
+const OPT_REGISTRY: &str = "registry";
+const OPT_TOKEN_FILE: &str = "token-file";
+const OPT_VERSIONS_FROM: &str = "versions-from";
+const OPT_ALLOW_YANKED: &str = "allow-yanked";
+const OPT_ALLOW_PRERELEASE: &str = "allow-prerelease";
+const OPT_CHECKSUM: &str = "checksum";
+const OPT_NO_VERIFY: &str = "no-verify";
+const OPT_LIST: &str = "list";
+const OPT_EXTRACT: &str = "extract";
+const OPT_FORCE: &str = "force";
+const OPT_STRIP_COMPONENTS: &str = "strip-components";
+const OPT_INCLUDE: &str = "include";
+const OPT_EXCLUDE: &str = "exclude";
+const OPT_CAT: &str = "cat";
+const OPT_LIST_FILES: &str = "list-files";
+const OPT_FORMAT: &str = "format";
+const OPT_OUTPUT: &str = "output";
+const OPT_MESSAGE_FORMAT: &str = "message-format";
+const OPT_CACHE_DIR: &str = "cache-dir";
+const OPT_NO_CACHE: &str = "no-cache";
+const OPT_OFFLINE: &str = "offline";
+const OPT_RETRIES: &str = "retries";
+const OPT_PROXY: &str = "proxy";
+const OPT_CACERT: &str = "cacert";
+const OPT_VERBOSE: &str = "verbose";
+const OPT_QUIET: &str = "quiet";
 
 /// Create the parser for application's command line.
 fn create_parser<'p>() -> Parser<'p> {
@@ -314,7 +641,7 @@ fn create_parser<'p>() -> Parser<'p> {
     parser
         .bin_name("cargo download")
         .about(*ABOUT)
-        .author(crate_authors!(", "))
+        .author(env!("CARGO_PKG_AUTHORS"))
 
         .setting(AppSettings::StrictUtf8)
 
@@ -323,17 +650,237 @@ fn create_parser<'p>() -> Parser<'p> {
         .setting(AppSettings::DeriveDisplayOrder)
         .setting(AppSettings::ColorNever)
 
+        // Subcommands are only recognized in place of the first crate,
+        // so a crate named like one can still be downloaded as e.g. `cache=*`.
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(SubCommand::with_name(CMD_CACHE)
+            .about("Manage the local cache of downloaded crate archives")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .setting(AppSettings::DeriveDisplayOrder)
+            .subcommand(SubCommand::with_name(CMD_CACHE_LIST)
+                .about("List the cached crate archives, along with their total size"))
+            .subcommand(SubCommand::with_name(CMD_CACHE_PRUNE)
+                .about("Remove cached crate archives")
+                .arg(Arg::with_name(OPT_OLDER_THAN)
+                    .long("older-than")
+                    .value_name("AGE")
+                    .required(false)
+                    .multiple(false)
+                    .takes_value(true)
+                    .help("Only remove archives that haven't been used for AGE")
+                    .long_help(concat!(
+                        "Only remove the archives that haven't been downloaded or used ",
+                        "for longer than AGE, like \"30d\" (the default unit), ",
+                        "\"12h\", \"90m\", or \"2w\".\n\n",
+                        "Without this flag, all the archives are removed.")))))
+
         .arg(Arg::with_name(ARG_CRATE)
             .value_name("CRATE[=VERSION]")
-            .required(true)
-            .help("Crate to download")
+            .required_unless(OPT_LOCKFILE)
+            .multiple(true)
+            .help("Crate(s) to download")
             .long_help(concat!(
                 "The crate to download.\n\n",
                 "This can be just a crate name (like \"foo\"), in which case ",
-                "the newest version of the crate is fetched. ",
+                "the newest stable version of the crate is fetched. ",
                 "Alternatively, the VERSION requirement can be given after ",
                 "the equal sign (=) in the usual Cargo.toml format ",
-                "(e.g. \"foo==0.9\" for the exact version).")))
+                "(e.g. \"foo==0.9\" for the exact version).\n\n",
+                "Several crates can be given to download them all at once, ",
+                "in which case each archive is written to the --output directory ",
+                "(current one by default) as $CRATE-$VERSION.crate.")))
+
+        .arg(Arg::with_name(OPT_LOCKFILE)
+            .long("lockfile")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with_all(&[ARG_CRATE, OPT_CHECKSUM, OPT_LIST])
+            .help("Download all the registry packages pinned in a Cargo.lock file")
+            .long_help(concat!(
+                "Path to a Cargo.lock file whose packages should be downloaded ",
+                "instead of the crates given as arguments.\n\n",
+                "The exact pinned versions are downloaded from the registries ",
+                "the lockfile names, and verified against its checksums. ",
+                "Path and git packages are skipped. ",
+                "Like with multiple crates, the archives are written to ",
+                "the --output directory (current one by default).")))
+
+        .arg(Arg::with_name(OPT_RECURSIVE)
+            .long("recursive").short("r")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .conflicts_with_all(&[OPT_LOCKFILE, OPT_LIST, OPT_CHECKSUM, OPT_VERSIONS_FROM])
+            .help("Download the crates along with all their dependencies")
+            .long_help(concat!(
+                "Resolve the dependencies of the crates recursively, ",
+                "and download the whole dependency tree to the --output directory ",
+                "(current one by default). The resolved tree is printed at the end.\n\n",
+                "Each version requirement resolves to the newest matching version, ",
+                "and dependencies for all target platforms are included. ",
+                "Optional dependencies are only followed when the features ",
+                "enabling them are requested.")))
+        .arg(Arg::with_name(OPT_BUILD_DEPS)
+            .long("build-deps")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .requires(OPT_RECURSIVE)
+            .help("Follow build dependencies too when downloading recursively"))
+        .arg(Arg::with_name(OPT_DEV_DEPS)
+            .long("dev-deps")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .requires(OPT_RECURSIVE)
+            .help("Follow dev dependencies of the given crates when downloading recursively"))
+        .arg(Arg::with_name(OPT_FEATURES)
+            .long("features").short("F")
+            .required(false)
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .value_name("FEATURES")
+            .requires(OPT_RECURSIVE)
+            .help("Features to enable on the given crates when downloading recursively"))
+        .arg(Arg::with_name(OPT_ALL_FEATURES)
+            .long("all-features")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .requires(OPT_RECURSIVE)
+            .help("Enable all features of the given crates when downloading recursively"))
+        .arg(Arg::with_name(OPT_NO_DEFAULT_FEATURES)
+            .long("no-default-features")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .requires(OPT_RECURSIVE)
+            .help("Don't enable the default features of the given crates when downloading recursively"))
+
+        .arg(Arg::with_name(OPT_JOBS)
+            .long("jobs").short("j")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .value_name("N")
+            .help("Number of crates to download concurrently (default: 4)"))
+
+        .arg(Arg::with_name(OPT_REGISTRY)
+            .long("registry")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .value_name("NAME")
+            .help("Registry to download the crate from")
+            .long_help(concat!(
+                "Name of an alternative registry to download the crate from.\n\n",
+                "The registry must be defined in the [registries] section ",
+                "of Cargo config (e.g. ~/.cargo/config.toml), ",
+                "or through the CARGO_REGISTRIES_<NAME>_INDEX environment variable. ",
+                "By default, crates are downloaded from crates.io.\n\n",
+                "Sparse (sparse+https://...) and file:// indexes are read directly. ",
+                "Git indexes can't be fetched, so they're read from Cargo's local copy ",
+                "(in $CARGO_HOME/registry/index), which Cargo must have fetched before.")))
+        .arg(Arg::with_name(OPT_TOKEN_FILE)
+            .long("token-file")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .value_name("FILE")
+            .requires(OPT_REGISTRY)
+            .help("File with the token of the registry")
+            .long_help(concat!(
+                "File with the token to authenticate to the alternative registry with.\n\n",
+                "By default, the token is taken from Cargo credentials ",
+                "(registries.<NAME>.token in ~/.cargo/credentials.toml), ",
+                "the CARGO_REGISTRIES_<NAME>_TOKEN environment variable, ",
+                "or the credential providers configured for the registry. ",
+                "The token is only ever sent to the registry's own host.")))
+
+        .arg(Arg::with_name(OPT_VERSIONS_FROM)
+            .long("versions-from")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .possible_values(&["index", "api"])
+            .value_name("SOURCE")
+            .help("Where to look up the available crate versions")
+            .long_help(concat!(
+                "Where to look up the available versions of the crate.\n\n",
+                "By default (\"index\"), versions are read from the registry index ",
+                "like Cargo itself does. Specify \"api\" to use the registry's web API ",
+                "instead, e.g. for registries that only expose the latter.\n\n",
+                "With --list, the web API is the default if the registry has one.")))
+
+        .arg(Arg::with_name(OPT_ALLOW_YANKED)
+            .long("allow-yanked")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .help("Consider yanked versions when matching version requirements")
+            .long_help(concat!(
+                "By default, versions that have been yanked from the registry ",
+                "are skipped when looking for the newest version matching the requirement. ",
+                "Pass this flag to consider them as well.\n\n",
+                "An exact version (e.g. \"foo==1.2.3\") is always downloaded, ",
+                "though a warning is logged if it has been yanked.")))
+
+        .arg(Arg::with_name(OPT_ALLOW_PRERELEASE)
+            .long("allow-prerelease")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .help("Consider pre-release versions when matching version requirements")
+            .long_help(concat!(
+                "By default, only stable versions are considered when looking for ",
+                "the newest version matching the requirement, ",
+                "unless the requirement itself names a pre-release (e.g. \"foo=>=1.0.0-beta\"). ",
+                "Pass this flag to consider pre-releases for any requirement, ",
+                "e.g. so that \"foo=^1.0\" can resolve to 1.1.0-alpha.1 ",
+                "(but not to 1.0.0-alpha.1, which comes before 1.0.0).")))
+
+        .arg(Arg::with_name(OPT_CHECKSUM)
+            .long("checksum")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .value_name("SHA256")
+            .conflicts_with(OPT_NO_VERIFY)
+            .help("Expected SHA256 checksum of the crate archive")
+            .long_help(concat!(
+                "Hex-encoded SHA256 checksum to verify the downloaded archive against.\n\n",
+                "By default, the checksum is taken from the registry index. ",
+                "If the archive doesn't match it, the download fails with exit code 80.")))
+        .arg(Arg::with_name(OPT_NO_VERIFY)
+            .long("no-verify")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .conflicts_with(OPT_CHECKSUM)
+            .help("Don't verify the checksum of the crate archive")
+            .long_help(concat!(
+                "Don't verify the downloaded archive against its checksum from the registry index ",
+                "(or the lockfile), which otherwise fails the download with exit code 80 ",
+                "if they don't match.")))
+
+        .arg(Arg::with_name(OPT_LIST)
+            .long("list")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .conflicts_with_all(&[OPT_EXTRACT, OPT_OUTPUT, OPT_CHECKSUM, OPT_NO_VERIFY])
+            .help("List available versions of the crate instead of downloading it")
+            .long_help(concat!(
+                "Print all versions of the crate that match the VERSION requirement ",
+                "(including yanked ones and pre-releases), newest first, ",
+                "without downloading anything.\n\n",
+                "Publish dates and archive sizes are only available from the registry's ",
+                "web API, which is therefore used by default in this mode ",
+                "if the registry has one.")))
 
         .arg(Arg::with_name(OPT_EXTRACT)
             .long("extract").short("x")
@@ -345,7 +892,114 @@ fn create_parser<'p>() -> Parser<'p> {
                 "Specify this flag to have the crate extracted automatically.",
                 "\n\nNote that unless changed via the --output flag, ",
                 "this will extract the files to a new subdirectory ",
-                "bearing the name of the downloaded crate archive.")))
+                "bearing the name of the downloaded crate archive.",
+                "\n\nArchive entries that could write outside of that directory ",
+                "(absolute paths, `..` components, symlinks or hard links pointing elsewhere), ",
+                "as well as device files and FIFOs, are rejected; ",
+                "the extraction then fails with exit code 79.")))
+
+        .arg(Arg::with_name(OPT_FORCE)
+            .long("force").short("f")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .requires(OPT_EXTRACT)
+            .help("Overwrite an existing directory when extracting the crate")
+            .long_help(concat!(
+                "The crate is extracted into a temporary directory first, ",
+                "and only moved into place once it's been fully extracted and verified. ",
+                "By default, this fails if the target directory already exists; ",
+                "pass this flag to replace it instead.\n\n",
+                "The existing directory is moved aside just before the crate is moved into place ",
+                "(and moved back if that fails), so it's briefly missing in between.")))
+
+        .arg(Arg::with_name(OPT_STRIP_COMPONENTS)
+            .long("strip-components")
+            .value_name("N")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .requires(OPT_EXTRACT)
+            .help("Strip N leading path components when extracting the crate")
+            .long_help(concat!(
+                "Remove the first N components from the paths of the extracted files, ",
+                "like `tar --strip-components` does. Files whose paths have ",
+                "no more than N components are skipped.\n\n",
+                "The crate's top-level $NAME-$VERSION/ directory doesn't count here, ",
+                "as its contents are always placed directly in the output directory. ",
+                "For example, --strip-components 1 extracts src/lib.rs as lib.rs.")))
+        .arg(Arg::with_name(OPT_INCLUDE)
+            .long("include")
+            .value_name("GLOB")
+            .required(false)
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .requires(OPT_EXTRACT)
+            .help("Only extract files matching the glob pattern (can be repeated)")
+            .long_help(concat!(
+                "Only extract the files whose paths within the crate ",
+                "(like \"src/lib.rs\") match any of the given glob patterns. ",
+                "Note that `*` doesn't match `/`; use `**` to match any subdirectories.\n\n",
+                "Patterns are matched before any --strip-components are applied.")))
+        .arg(Arg::with_name(OPT_EXCLUDE)
+            .long("exclude")
+            .value_name("GLOB")
+            .required(false)
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .requires(OPT_EXTRACT)
+            .help("Don't extract files matching the glob pattern (can be repeated)")
+            .long_help(concat!(
+                "Skip the files whose paths within the crate match any of the given ",
+                "glob patterns, even if they're matched by --include.")))
+
+        .arg(Arg::with_name(OPT_CAT)
+            .long("cat")
+            .value_name("PATH")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .conflicts_with_all(&[OPT_LIST, OPT_EXTRACT, OPT_OUTPUT, OPT_LOCKFILE, OPT_RECURSIVE])
+            .help("Print a single file from the crate instead of its archive")
+            .long_help(concat!(
+                "Write the content of given file from the crate to standard output, ",
+                "without extracting anything. The PATH is relative to the crate's root ",
+                "(e.g. \"build.rs\" or \"src/lib.rs\").\n\n",
+                "The file is only printed once the archive's checksum has been verified. ",
+                "If it's not in the crate, files with similar paths are suggested instead. ",
+                "Symlinks and hard links in the crate aren't followed, ",
+                "so asking for one is an error.")))
+
+        .arg(Arg::with_name(OPT_LIST_FILES)
+            .long("list-files")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .conflicts_with_all(&[
+                OPT_LIST, OPT_EXTRACT, OPT_CAT, OPT_OUTPUT, OPT_LOCKFILE, OPT_RECURSIVE])
+            .help("List the files in the crate instead of outputting its archive")
+            .long_help(concat!(
+                "Print the path, size, mode, and type of every entry ",
+                "in the crate's archive, followed by their totals, ",
+                "without writing the archive anywhere.\n\n",
+                "Entries are listed exactly as they're stored in the archive, ",
+                "including any that --extract would reject.")))
+        .arg(Arg::with_name(OPT_FORMAT)
+            .long("format")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .possible_values(&["human", "json"])
+            .value_name("FORMAT")
+            .requires(OPT_LIST_FILES)
+            .help("Format of the --list-files output")
+            .long_help(concat!(
+                "Format of the file listing printed by --list-files.\n\n",
+                "By default (\"human\"), it's an aligned table. Specify \"json\" ",
+                "to get a JSON object with the crate's name and version, ",
+                "its \"files\", and their \"totals\" instead.")))
 
         .arg(Arg::with_name(OPT_OUTPUT)
             .long("output").short("o")
@@ -358,20 +1012,250 @@ fn create_parser<'p>() -> Parser<'p> {
                 "while the extract one (-x flag) is placed in a directory corresponding ",
                 "to crate's name.\n",
                 "This flag allows to change that by providing an explicit ",
-                "file or directory path.")))
+                "file or directory path.\n\n",
+                "When downloading more than one crate, this is the directory ",
+                "where all their archives (or extracted contents) are placed.")))
+        .arg(Arg::with_name(OPT_MESSAGE_FORMAT)
+            .long("message-format")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .possible_values(&["human", "json"])
+            .value_name("FORMAT")
+            .conflicts_with_all(&[OPT_LIST, OPT_LIST_FILES, OPT_CAT])
+            .help("Format of the messages about downloaded crates")
+            .long_help(concat!(
+                "By default (\"human\"), the results are only reported in the logs.\n\n",
+                "Specify \"json\" to print a JSON object on a single line of stdout ",
+                "for every crate that has been downloaded, with its name, resolved version, ",
+                "requirement, source URL, size, SHA256 checksum, output path ",
+                "or extraction directory, and whether it's been yanked. ",
+                "Crate archives are then always written to files ",
+                "(named after the crate if --output isn't given), never to stdout.")))
+
+        .arg(Arg::with_name(OPT_CACHE_DIR)
+            .long("cache-dir")
+            .value_name("DIR")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .global(true)
+            .help("Directory of the download cache")
+            .long_help(concat!(
+                "Crate archives are cached once they've been downloaded, ",
+                "and reused instead of downloading them again as long as their checksum ",
+                "is known upfront (which it is unless --no-verify is used).\n\n",
+                "By default, the cache is in $XDG_CACHE_HOME/cargo-download if the variable ",
+                "is set, or $CARGO_HOME/cargo-download (~/.cargo/cargo-download) otherwise. ",
+                "Use the `cache` subcommand to list or prune the cached archives.")))
+        .arg(Arg::with_name(OPT_NO_CACHE)
+            .long("no-cache")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .help("Neither read from nor write to the download cache")
+            .long_help(concat!(
+                "Always download the crates, without reading from or writing to ",
+                "the download cache, nor reusing the archives that Cargo itself ",
+                "has downloaded (to $CARGO_HOME/registry/cache).")))
+        .arg(Arg::with_name(OPT_OFFLINE)
+            .long("offline")
+            .required(false)
+            .multiple(false)
+            .takes_value(false)
+            .conflicts_with(OPT_NO_CACHE)
+            .help("Never access the network")
+            .long_help(concat!(
+                "Only use crate archives that are available locally, ",
+                "either in the download cache or in Cargo's own cache ",
+                "($CARGO_HOME/registry/cache), and fail if a crate isn't there.\n\n",
+                "Versions are resolved against Cargo's local copies of registry indices ",
+                "($CARGO_HOME/registry/index), so they only include crates ",
+                "that Cargo has looked up before.")))
+        .arg(Arg::with_name(OPT_RETRIES)
+            .long("retries")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .value_name("N")
+            .help("Number of times to retry failed network requests (default: 3)")
+            .long_help(concat!(
+                "Number of times to retry a network request that failed ",
+                "because of a connection error or a server response like 429 Too Many Requests ",
+                "or 503 Service Unavailable (default: 3).\n\n",
+                "Retries are delayed with an exponential backoff, ",
+                "or as requested by the server's Retry-After header.")))
+        .arg(Arg::with_name(OPT_PROXY)
+            .long("proxy")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .value_name("URL")
+            .conflicts_with(OPT_OFFLINE)
+            .help("HTTP proxy to use")
+            .long_help(concat!(
+                "HTTP proxy to send the requests through, as URL or just HOST:PORT.\n\n",
+                "By default, the proxy is taken from Cargo config (http.proxy) ",
+                "or the https_proxy and http_proxy environment variables, ",
+                "depending on the scheme of the requested URL. ",
+                "Either way, hosts listed in NO_PROXY are connected to directly.")))
+        .arg(Arg::with_name(OPT_CACERT)
+            .long("cacert")
+            .required(false)
+            .multiple(false)
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with(OPT_OFFLINE)
+            .help("Additional certificate authorities to trust")
+            .long_help(concat!(
+                "PEM file with certificate authorities to trust, ",
+                "in addition to the system ones (e.g. those of a corporate proxy).\n\n",
+                "By default, it's taken from Cargo config (http.cainfo). ",
+                "Other HTTP settings from there, like http.timeout (how long a connection ",
+                "may stall) and http.user-agent, are also used. ",
+                "Checking certificate revocation (http.check-revoke) isn't supported, ",
+                "and the setting is ignored with a warning.")))
 
         // Verbosity flags.
         .arg(Arg::with_name(OPT_VERBOSE)
             .long("verbose").short("v")
             .multiple(true)
+            .global(true)
             .conflicts_with(OPT_QUIET)
             .help("Increase logging verbosity"))
         .arg(Arg::with_name(OPT_QUIET)
             .long("quiet").short("q")
             .multiple(true)
+            .global(true)
             .conflicts_with(OPT_VERBOSE)
             .help("Decrease logging verbosity"))
 
         .help_short("H")
         .version_short("V")
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use super::{parse_duration, parse_from_argv, ArgsError, CacheCommand, Crate, CrateError,
+                Options, Output};
+
+    #[test]
+    fn crate_specs() {
+        assert_eq!("foo", Crate::from_str("foo").unwrap().name());
+        assert_eq!("foo-bar_2", Crate::from_str("foo-bar_2=1.0").unwrap().name());
+        assert!(Crate::from_str("foo==1.2.3").unwrap().exact_version().is_some());
+        for spec in &["=1.0", "", "aéb=1", "aéb", "foo/bar=1"] {
+            match Crate::from_str(spec) {
+                Err(CrateError::Name(_)) => {}
+                r => panic!("unexpected result for {:?}: {:?}", spec, r),
+            }
+        }
+    }
+
+    fn parse(args: &[&str]) -> Result<Options, ArgsError> {
+        let argv = Some("cargo-download").into_iter().chain(args.iter().cloned());
+        parse_from_argv(argv.map(String::from))
+    }
+
+    #[test]
+    fn several_crates() {
+        let opts = parse(&["foo", "bar=1.0", "baz==2.0.0"]).unwrap();
+        let names: Vec<_> = opts.crates.iter().map(|c| c.name()).collect();
+        assert_eq!(vec!["foo", "bar", "baz"], names);
+        assert!(opts.multiple());
+        assert!(opts.crates[2].exact_version().is_some());
+        assert!(!parse_from_argv(vec!["cargo", "download", "foo"].into_iter().map(String::from))
+            .unwrap().multiple());
+    }
+
+    #[test]
+    fn jobs() {
+        assert_eq!(8, parse(&["-j", "8", "foo"]).unwrap().jobs);
+        assert_eq!(super::DEFAULT_JOBS, parse(&["foo"]).unwrap().jobs);
+        for jobs in &["0", "-1", "many"] {
+            match parse(&[&format!("--jobs={}", jobs), "foo"]) {
+                Err(ArgsError::Jobs(_)) => {}
+                r => panic!("unexpected result for {:?}: {:?}", jobs, r),
+            }
+        }
+    }
+
+    #[test]
+    fn filters() {
+        let opts = parse(&["-x", "foo", "--strip-components", "1",
+                                     "--include", "src/**", "--include", "*.toml",
+                                     "--exclude", "**/tests/*"]).unwrap();
+        assert_eq!(1, opts.filter.strip_components);
+        let include: Vec<_> = opts.filter.include.iter().map(|p| p.as_str()).collect();
+        assert_eq!(vec!["src/**", "*.toml"], include);
+        assert_eq!("**/tests/*", opts.filter.exclude[0].as_str());
+        assert!(parse(&["-x", "foo"]).unwrap().filter.is_noop());
+        match parse(&["-x", "foo", "--strip-components=-1"]) {
+            Err(ArgsError::StripComponents(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match parse(&["-x", "foo", "--include", "src/[a"]) {
+            Err(ArgsError::Glob(ref g, _)) if g == "src/[a" => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn durations() {
+        let day = 24 * 60 * 60;
+        assert_eq!(Some(Duration::from_secs(30)), parse_duration("30s"));
+        assert_eq!(Some(Duration::from_secs(15 * 60)), parse_duration("15m"));
+        assert_eq!(Some(Duration::from_secs(12 * 60 * 60)), parse_duration("12h"));
+        assert_eq!(Some(Duration::from_secs(2 * 7 * day)), parse_duration("2w"));
+        assert_eq!(Some(Duration::from_secs(30 * day)), parse_duration("30"));
+        assert_eq!(Some(Duration::from_secs(3 * day)), parse_duration(" 3 d "));
+        for s in &["", "d", "3y", "1.5h", "-1d", "99999999999999999999w"] {
+            assert_eq!(None, parse_duration(s), "{:?}", s);
+        }
+
+        let opts = parse(&["cache", "prune", "--older-than", "2w"]).unwrap();
+        assert_eq!(Some(CacheCommand::Prune{older_than: Some(Duration::from_secs(2 * 7 * day))}),
+                   opts.cache_command);
+        match parse(&["cache", "prune", "--older-than", "soon"]) {
+            Err(ArgsError::Duration(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn conflicts() {
+        // With several crates, --output is a directory.
+        let opts = parse(&["-o", "out", "foo", "bar"]).unwrap();
+        assert_eq!(Some(Output::Path(PathBuf::from("out"))), opts.output);
+
+        let several: &[&[&str]] = &[
+            &["-o", "-", "foo", "bar"],
+            &["--list-files", "foo", "bar"],
+            &["--cat", "Cargo.toml", "foo", "bar"],
+            &["-o", "-", "-r", "foo"],
+        ];
+        for args in several {
+            match parse(args) {
+                Err(ArgsError::MultipleToStdout) => {}
+                r => panic!("unexpected result for {:?}: {:?}", args, r),
+            }
+        }
+        let checksum = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        match parse(&["--checksum", checksum, "foo", "bar"]) {
+            Err(ArgsError::AmbiguousChecksum) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match parse(&["-x", "-o", "-", "foo"]) {
+            Err(ArgsError::CantExtractToStdout) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match parse(&["--message-format", "json", "-o", "-", "foo"]) {
+            Err(ArgsError::JsonToStdout) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Module for the local cache of downloaded crate archives.
+//!
+//! Published crate archives never change, so once an archive has been downloaded
+//! and its checksum is known, it can be reused instead of fetching it again.
+//! Archives are stored as `$CACHE/$REGISTRY/$CRATE/$VERSION-$SHA256.crate`,
+//! where `$REGISTRY` is the registry's name combined with a hash of its index URL.
+//!
+//! Archives that Cargo itself has downloaded (to `$CARGO_HOME/registry/cache`)
+//! are reused as well, though that cache is never written to.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use hex;
+use semver::Version;
+use sha2::{Digest, Sha256};
+use tempfile::{self, NamedTempFile};
+
+use cargo_index::registry_dirs;
+use config::cargo_home;
+use registry::Registry;
+
+
+/// Cache of crate archives in a local directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    #[inline]
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Cache{dir: dir.into()}
+    }
+
+    /// Default location of the cache:
+    /// `$XDG_CACHE_HOME/cargo-download` if the variable is set,
+    /// `$CARGO_HOME/cargo-download` otherwise.
+    pub fn default_dir() -> Option<PathBuf> {
+        env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()).map(PathBuf::from)
+            .or_else(cargo_home)
+            .map(|d| d.join("cargo-download"))
+    }
+
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Open the cached archive of given crate with given SHA256 checksum, if there is one.
+    ///
+    /// The archive is marked as used, so that it's kept around by `prune`.
+    pub fn open(&self, registry: &Registry, name: &str, version: &Version,
+                checksum: &str) -> Option<(File, PathBuf)> {
+        let path = self.crate_dir(registry, name)
+            .join(format!("{}-{}.crate", version, checksum.to_lowercase()));
+        let file = File::open(&path).ok()?;
+        if let Err(e) = file.set_modified(SystemTime::now()) {
+            trace!("Couldn't update modification time of {}: {}", path.display(), e);
+        }
+        Some((file, path))
+    }
+
+    /// Start adding an archive of given crate to the cache.
+    pub fn writer(&self, registry: &Registry, name: &str,
+                  version: &Version) -> io::Result<CacheWriter> {
+        let dir = self.crate_dir(registry, name);
+        fs::create_dir_all(&dir)?;
+        let file = tempfile::Builder::new()
+            .prefix(&format!(".{}-", version)).suffix(".part")
+            .tempfile_in(&dir)?;
+        Ok(CacheWriter{file, dir, version: version.clone()})
+    }
+
+    /// List all the archives in the cache, ordered by registry, crate, and version.
+    pub fn entries(&self) -> io::Result<Vec<CacheEntry>> {
+        let mut entries = vec![];
+        for registry in read_dir(&self.dir)? {
+            for crate_ in read_dir(&registry)? {
+                for path in read_dir(&crate_)? {
+                    if let Some(entry) = CacheEntry::from_path(&path)? {
+                        entries.push(entry);
+                    }
+                }
+            }
+        }
+        entries.sort_by(|a, b| (&a.registry, &a.name, &a.version)
+            .cmp(&(&b.registry, &b.name, &b.version)));
+        Ok(entries)
+    }
+
+    /// Remove the archives that haven't been used for longer than `max_age`
+    /// (or all of them if it's `None`). Returns the removed entries.
+    pub fn prune(&self, max_age: Option<Duration>) -> io::Result<Vec<CacheEntry>> {
+        let now = SystemTime::now();
+        let mut removed = vec![];
+        for entry in self.entries()? {
+            let age = now.duration_since(entry.last_used).unwrap_or_default();
+            if max_age.is_some_and(|max_age| age <= max_age) {
+                continue;
+            }
+            debug!("Removing cached archive {}", entry.path.display());
+            fs::remove_file(&entry.path)?;
+            // Remove the crate's and registry's directories if they're now empty.
+            let mut dir = entry.path.parent();
+            while let Some(d) = dir.filter(|&d| d != self.dir) {
+                if fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+            removed.push(entry);
+        }
+        Ok(removed)
+    }
+
+    fn crate_dir(&self, registry: &Registry, name: &str) -> PathBuf {
+        self.dir.join(registry.id()).join(name.to_lowercase())
+    }
+}
+
+/// Find the archive of given crate in Cargo's own cache of crate archives
+/// (usually `$CARGO_HOME/registry/cache`), provided it has given SHA256 checksum.
+///
+/// Cargo names the cache directory of each registry after the host of its index
+/// and a hash of the index URL, which we don't reproduce. So the directories are
+/// matched by host only, and an archive found there may come from another registry
+/// on the same host: it's only used if it has the right checksum.
+/// They're tried from the most recently used one, up to the first such archive.
+pub fn find_cargo_archive(cargo_cache: &Path, registry: &Registry, name: &str,
+                          version: &Version, checksum: &str) -> Option<PathBuf> {
+    let file_name = format!("{}-{}.crate", name, version);
+    for dir in registry_dirs(cargo_cache, &registry.cargo_hosts()) {
+        let path = dir.join(&file_name);
+        match sha256_file(&path) {
+            Ok(actual) if actual.eq_ignore_ascii_case(checksum) => return Some(path),
+            Ok(actual) => debug!("Ignoring {} from Cargo's cache with different checksum {}",
+                path.display(), actual),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => debug!("Cannot read {} from Cargo's cache: {}", path.display(), e),
+        }
+    }
+    None
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 8192];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.input(&buf[..n]),
+        }
+    }
+    Ok(hex::encode(hasher.result()))
+}
+
+
+/// List the non-hidden entries of a directory, if it exists.
+fn read_dir(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut paths = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with('.') {
+            paths.push(entry.path());
+        }
+    }
+    Ok(paths)
+}
+
+
+/// Archive being added to the cache.
+///
+/// Unless it's persisted, the partially written archive is removed when this is dropped.
+#[derive(Debug)]
+pub struct CacheWriter {
+    file: NamedTempFile,
+    /// Directory of the crate's cached archives.
+    dir: PathBuf,
+    version: Version,
+}
+
+impl CacheWriter {
+    /// Move the fully written archive to its place in the cache,
+    /// under its actual SHA256 checksum.
+    pub fn persist(self, checksum: &str) -> io::Result<PathBuf> {
+        let path = self.dir.join(format!("{}-{}.crate", self.version, checksum));
+        self.file.persist(&path).map_err(|e| e.error)?;
+        Ok(path)
+    }
+}
+
+impl Write for CacheWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+
+/// Single archive in the cache.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    /// Identifier of the registry the crate comes from (see `Registry::id`).
+    pub registry: String,
+    pub name: String,
+    pub version: Version,
+    pub checksum: String,
+    /// Size of the archive in bytes.
+    pub size: u64,
+    /// When the archive has been downloaded or used last.
+    pub last_used: SystemTime,
+}
+
+impl CacheEntry {
+    /// Describe the cached archive at given path.
+    /// Returns `None` if it doesn't look like one.
+    fn from_path(path: &Path) -> io::Result<Option<Self>> {
+        let parse = || {
+            let file_name = path.file_name()?.to_str()?.strip_suffix(".crate")?;
+            let (version, checksum) = file_name.rsplit_once('-')?;
+            let name = path.parent()?.file_name()?.to_str()?;
+            let registry = path.parent()?.parent()?.file_name()?.to_str()?;
+            Some((registry.to_owned(), name.to_owned(),
+                  Version::parse(version).ok()?, checksum.to_owned()))
+        };
+        let (registry, name, version, checksum) = match parse() {
+            Some(parsed) => parsed,
+            None => {
+                trace!("Ignoring unexpected file in the cache: {}", path.display());
+                return Ok(None);
+            }
+        };
+        let metadata = fs::metadata(path)?;
+        Ok(Some(CacheEntry{
+            path: path.to_owned(),
+            registry, name, version, checksum,
+            size: metadata.len(),
+            last_used: metadata.modified()?,
+        }))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::time::{Duration, SystemTime};
+
+    use semver::Version;
+    use tempfile;
+
+    use registry::Registry;
+    use super::{find_cargo_archive, Cache};
+
+    #[test]
+    fn cached_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path());
+        let registry = Registry::crates_io();
+        let version = Version::parse("1.0.0-beta.1").unwrap();
+        assert!(cache.open(&registry, "Foo", &version, "abcd").is_none());
+
+        let mut writer = cache.writer(&registry, "Foo", &version).unwrap();
+        writer.write_all(b"archive").unwrap();
+        let path = writer.persist("abcd").unwrap();
+        // Unfinished archives aren't left behind.
+        drop(cache.writer(&registry, "Foo", &version).unwrap());
+        assert_eq!(1, fs::read_dir(path.parent().unwrap()).unwrap().count());
+
+        let (mut file, _) = cache.open(&registry, "Foo", &version, "ABCD").unwrap();
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!("archive", content);
+        assert!(cache.open(&Registry::crates_io(), "foo", &version, "abce").is_none());
+
+        let entries = cache.entries().unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(("foo", &version, "abcd", 7),
+            (&entries[0].name[..], &entries[0].version, &entries[0].checksum[..], entries[0].size));
+        assert_eq!(registry.id(), entries[0].registry);
+    }
+
+    #[test]
+    fn pruning() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path());
+        let registry = Registry::crates_io();
+        for v in &["1.0.0", "2.0.0"] {
+            let version = Version::parse(v).unwrap();
+            cache.writer(&registry, "foo", &version).unwrap().persist("abcd").unwrap();
+        }
+        let old = cache.entries().unwrap()[0].path.clone();
+        let month_ago = SystemTime::now() - Duration::from_secs(30 * 24 * 3600);
+        File::options().write(true).open(&old).unwrap().set_modified(month_ago).unwrap();
+
+        let removed = cache.prune(Some(Duration::from_secs(7 * 24 * 3600))).unwrap();
+        assert_eq!(vec![old], removed.into_iter().map(|e| e.path).collect::<Vec<_>>());
+        assert_eq!(1, cache.entries().unwrap().len());
+
+        assert_eq!(1, cache.prune(None).unwrap().len());
+        // Empty directories are removed as well.
+        assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn cargo_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = Registry::crates_io();
+        let version = Version::parse("1.0.0").unwrap();
+        let checksum = "0eb3e36bfb24dcd9bb1d1bece1531216b59539a8fde17ee80224af0653c92aa3";
+        let archive = |dir_name: &str, content: &str| {
+            fs::create_dir(dir.path().join(dir_name)).unwrap();
+            fs::write(dir.path().join(dir_name).join("foo-1.0.0.crate"), content).unwrap();
+            dir.path().join(dir_name).join("foo-1.0.0.crate")
+        };
+        archive("index.crates.io.evil-0123456789abcdef", "archive");
+        archive("github.com-1ecc6299db9ec823", "tampered");
+        assert_eq!(None, find_cargo_archive(dir.path(), &registry, "foo", &version, checksum));
+
+        let path = archive("index.crates.io-1949cf8c6b5b557f", "archive");
+        assert_eq!(Some(path),
+            find_cargo_archive(dir.path(), &registry, "foo", &version, checksum));
+        assert_eq!(None, find_cargo_archive(dir.path(), &registry, "bar", &version, checksum));
+    }
+}
@@ -0,0 +1,183 @@
+//! Module for reading Cargo's local copies of registry indices,
+//! which are used in offline mode instead of fetching the index files.
+//!
+//! Cargo keeps them in `$CARGO_HOME/registry/index/$HOST-$HASH`, where `$HOST`
+//! is the host of the index URL. For sparse indices, the directory only contains
+//! `config.json` and the `.cache` entries of the crates Cargo has looked up.
+//! For git indices, it's a git repository (with the same `.cache` entries),
+//! whose files are read with the `git` command if it's available.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+
+/// Version of Cargo's index cache entries that we can read.
+const CACHE_VERSION: u8 = 3;
+
+/// Git ref that Cargo fetches registry indices into.
+const GIT_INDEX_REF: &str = "refs/remotes/origin/HEAD";
+
+
+/// Read a file at given path relative to the index root from Cargo's local copies
+/// of the index in `cargo_index_dir`, named after any of given hosts.
+///
+/// Index files of crates are returned in the same format as they're served,
+/// even if they come from Cargo's own cache of them.
+/// Returns `None` if the file isn't available locally.
+pub fn read_file(cargo_index_dir: &Path, hosts: &[&str], path: &str) -> Option<Vec<u8>> {
+    for dir in registry_dirs(cargo_index_dir, hosts) {
+        if path != "config.json" {
+            let cache_file = dir.join(".cache").join(path);
+            match fs::read(&cache_file) {
+                Ok(bytes) => match parse_cache_entry(&bytes) {
+                    Some(content) => {
+                        trace!("Read index file from Cargo's cache: {}", cache_file.display());
+                        return Some(content);
+                    }
+                    None => debug!("Skipping unsupported Cargo index cache file {}",
+                        cache_file.display()),
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => debug!("Cannot read {}: {}", cache_file.display(), e),
+            }
+        }
+        // Sparse indices have their config.json here, and older Cargo versions
+        // used to check out the files of git indices.
+        if let Ok(bytes) = fs::read(dir.join(path)) {
+            trace!("Read index file {}", dir.join(path).display());
+            return Some(bytes);
+        }
+        if let Some(bytes) = read_git_file(&dir, path) {
+            return Some(bytes);
+        }
+    }
+    None
+}
+
+/// Cargo's directories for the registry with given hosts (named `$HOST-$HASH`)
+/// within `parent`, e.g. its local copies of the index, most recently updated first.
+///
+/// They're matched by host only, since we don't reproduce Cargo's hash of the index URL,
+/// so they may also belong to other registries on the same host.
+pub fn registry_dirs(parent: &Path, hosts: &[&str]) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(parent) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Cannot read Cargo's registry directory {}: {}", parent.display(), e);
+            return vec![];
+        }
+    };
+    let mut dirs: Vec<_> = entries.filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_str().is_some_and(|name| hosts.iter().any(|h| {
+            name.strip_prefix(h).is_some_and(|hash| hash.starts_with('-'))
+        })))
+        .map(|e| {
+            let modified = e.metadata().and_then(|m| m.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+            (modified, e.path())
+        })
+        .collect();
+    dirs.sort_by(|a, b| b.cmp(a));
+    dirs.into_iter().map(|(_, dir)| dir).collect()
+}
+
+/// Parse an entry of Cargo's index cache back into the content of the index file.
+///
+/// The entry consists of the cache version byte, the index format version
+/// (as a 32-bit little-endian integer), and then NUL-terminated strings:
+/// the index revision, followed by pairs of crate versions and their JSON lines.
+fn parse_cache_entry(bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.first() != Some(&CACHE_VERSION) || bytes.len() < 5 {
+        return None;
+    }
+    let mut fields = bytes[5..].split(|&b| b == 0);
+    fields.next()?;  // index revision
+    let mut content = vec![];
+    while let (Some(version), Some(json)) = (fields.next(), fields.next()) {
+        if version.is_empty() {
+            break;
+        }
+        content.extend_from_slice(json);
+        content.push(b'\n');
+    }
+    Some(content)
+}
+
+/// Read a file from Cargo's git repository of the index, using the `git` command.
+fn read_git_file(dir: &Path, path: &str) -> Option<Vec<u8>> {
+    let git_dir = dir.join(".git");
+    let git_dir = if git_dir.is_dir() { git_dir } else { dir.to_owned() };
+    if !git_dir.join("HEAD").is_file() {
+        return None;
+    }
+    let output = Command::new("git")
+        .arg("--git-dir").arg(&git_dir)
+        .arg("show").arg(format!("{}:{}", GIT_INDEX_REF, path))
+        .output();
+    match output {
+        Ok(ref output) if output.status.success() => {
+            trace!("Read index file {} from git repository {}", path, git_dir.display());
+            Some(output.stdout.clone())
+        }
+        Ok(_) => None,
+        Err(e) => {
+            debug!("Cannot run git to read {} from {}: {}", path, git_dir.display(), e);
+            None
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempfile;
+    use super::{parse_cache_entry, read_file};
+
+    /// Build an index cache entry like Cargo does.
+    fn cache_entry(lines: &[(&str, &str)]) -> Vec<u8> {
+        let mut bytes = vec![3, 2, 0, 0, 0];
+        bytes.extend_from_slice(b"etag: \"abcd\"\0");
+        for &(version, json) in lines {
+            bytes.extend_from_slice(version.as_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(json.as_bytes());
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    #[test]
+    fn cache_entries() {
+        let entry = cache_entry(&[
+            ("1.0.0", r#"{"name":"foo","vers":"1.0.0"}"#),
+            ("1.1.0", r#"{"name":"foo","vers":"1.1.0"}"#),
+        ]);
+        assert_eq!(concat!(
+            r#"{"name":"foo","vers":"1.0.0"}"#, "\n",
+            r#"{"name":"foo","vers":"1.1.0"}"#, "\n",
+        ).as_bytes(), &parse_cache_entry(&entry).unwrap()[..]);
+        assert_eq!(Some(vec![]), parse_cache_entry(&cache_entry(&[])));
+        assert_eq!(None, parse_cache_entry(&[1, 0]));
+    }
+
+    #[test]
+    fn local_index_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = dir.path().join("index.crates.io-1949cf8c6b5b557f");
+        fs::create_dir_all(index.join(".cache/3/f")).unwrap();
+        fs::write(index.join("config.json"), r#"{"dl":"https://example.com"}"#).unwrap();
+        fs::write(index.join(".cache/3/f/foo"),
+            cache_entry(&[("1.0.0", r#"{"name":"foo","vers":"1.0.0"}"#)])).unwrap();
+
+        let hosts = ["index.crates.io"];
+        assert_eq!(br#"{"dl":"https://example.com"}"#.to_vec(),
+            read_file(dir.path(), &hosts, "config.json").unwrap());
+        assert_eq!(b"{\"name\":\"foo\",\"vers\":\"1.0.0\"}\n".to_vec(),
+            read_file(dir.path(), &hosts, "3/f/foo").unwrap());
+        assert_eq!(None, read_file(dir.path(), &hosts, "3/b/bar"));
+        assert_eq!(None, read_file(dir.path(), &["example.com"], "3/f/foo"));
+    }
+}
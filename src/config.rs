@@ -0,0 +1,273 @@
+//! Module for reading Cargo's own configuration.
+//!
+//! This mimics the way Cargo looks for its config files
+//! (https://doc.rust-lang.org/cargo/reference/config.html)
+//! closely enough for us to find things like registry definitions.
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use toml::Value as Toml;
+
+
+/// Merged view of all Cargo configuration files that apply to current directory.
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Parsed config files, in the order of decreasing precedence.
+    files: Vec<(PathBuf, Toml)>,
+}
+
+impl fmt::Debug for Config {
+    /// Only the paths of the config files are shown,
+    /// since their contents include registry tokens.
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Config")
+            .field("files", &self.files.iter().map(|(path, _)| path).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Config {
+    /// Load the configuration from all the files Cargo would consult
+    /// if it was ran in the current directory.
+    ///
+    /// This includes Cargo's credentials file, which holds the registry tokens.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut files = vec![];
+        let credentials = cargo_home().and_then(|home| credentials_file_in(&home));
+        for path in credentials.into_iter().chain(config_file_paths()) {
+            trace!("Reading Cargo config file {}", path.display());
+            let content = fs::read_to_string(&path)
+                .map_err(|e| ConfigError::Io(path.clone(), e))?;
+            let toml = content.parse::<Toml>()
+                .map_err(|e| ConfigError::Parse(path.clone(), e))?;
+            files.push((path, toml));
+        }
+        debug!("Found {} Cargo config file(s)", files.len());
+        Ok(Config{files})
+    }
+
+    /// Retrieve a string value of given config key,
+    /// specified as a sequence of its dotted path segments.
+    ///
+    /// Like in Cargo, the value can be overridden via an environment variable
+    /// (e.g. `CARGO_REGISTRIES_FOO_INDEX` for `registries.foo.index`).
+    pub fn get_str(&self, key: &[&str]) -> Option<String> {
+        if let Ok(value) = env::var(env_var_name(key)) {
+            return Some(value);
+        }
+        self.get(key).and_then(|v| v.as_str()).map(|s| s.to_owned())
+    }
+
+    /// Retrieve an integer value of given config key,
+    /// which can be overridden via an environment variable like in `get_str`.
+    pub fn get_int(&self, key: &[&str]) -> Result<Option<i64>, ConfigError> {
+        if let Ok(value) = env::var(env_var_name(key)) {
+            return value.parse().map(Some).map_err(|_| ConfigError::Value(key.join("."), value));
+        }
+        match self.get(key) {
+            Some(value) => value.as_integer().map(Some)
+                .ok_or_else(|| ConfigError::Value(key.join("."), value.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieve a boolean value of given config key,
+    /// which can be overridden via an environment variable like in `get_str`.
+    pub fn get_bool(&self, key: &[&str]) -> Result<Option<bool>, ConfigError> {
+        if let Ok(value) = env::var(env_var_name(key)) {
+            return value.parse().map(Some).map_err(|_| ConfigError::Value(key.join("."), value));
+        }
+        match self.get(key) {
+            Some(value) => value.as_bool().map(Some)
+                .ok_or_else(|| ConfigError::Value(key.join("."), value.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieve a path value of given config key,
+    /// which can be overridden via an environment variable like in `get_str`.
+    ///
+    /// As in Cargo, relative paths in config files are relative to the directory
+    /// containing the `.cargo` directory the file is in.
+    pub fn get_path(&self, key: &[&str]) -> Option<PathBuf> {
+        if let Some(value) = env::var_os(env_var_name(key)) {
+            return Some(PathBuf::from(value));
+        }
+        self.files.iter().find_map(|(file, toml)| {
+            let value = key.iter().try_fold(toml, |value, k| value.get(k))?.as_str()?;
+            let base = file.parent().and_then(|dir| dir.parent());
+            Some(base.map_or_else(|| PathBuf::from(value), |base| base.join(value)))
+        })
+    }
+
+    /// Retrieve a command with its arguments from given config key,
+    /// which can be overridden via an environment variable like in `get_str`.
+    ///
+    /// As in Cargo, the value can be either a string (split on whitespace)
+    /// or an array of strings.
+    pub fn get_args(&self, key: &[&str]) -> Result<Option<Vec<String>>, ConfigError> {
+        if let Ok(value) = env::var(env_var_name(key)) {
+            return Ok(Some(value.split_whitespace().map(|s| s.to_owned()).collect()));
+        }
+        let value = match self.get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let args = match *value {
+            Toml::String(ref s) => Some(s.split_whitespace().map(|s| s.to_owned()).collect()),
+            Toml::Array(ref a) => a.iter().map(|v| v.as_str().map(|s| s.to_owned())).collect(),
+            _ => None,
+        };
+        args.filter(|a: &Vec<_>| !a.is_empty())
+            .map(Some)
+            .ok_or_else(|| ConfigError::Value(key.join("."), value.to_string()))
+    }
+
+    /// Retrieve a raw value of given config key from the config files
+    /// (without consulting the environment).
+    pub fn get(&self, key: &[&str]) -> Option<&Toml> {
+        self.files.iter().filter_map(|(_, toml)| {
+            key.iter().try_fold(toml, |value, k| value.get(k))
+        }).next()
+    }
+
+    /// Configuration consisting of a single file with given content.
+    #[cfg(test)]
+    pub fn from_toml(toml: &str) -> Self {
+        Config{files: vec![("config.toml".into(), toml.parse().unwrap())]}
+    }
+}
+
+
+/// Return the path to Cargo's home directory ($CARGO_HOME).
+pub fn cargo_home() -> Option<PathBuf> {
+    env::var_os("CARGO_HOME").map(PathBuf::from)
+        .or_else(|| env::home_dir().map(|h| h.join(".cargo")))
+}
+
+/// Name of the environment variable that overrides given config key.
+fn env_var_name(key: &[&str]) -> String {
+    let key = key.join("_").to_uppercase().replace('-', "_");
+    format!("CARGO_{}", key)
+}
+
+/// Paths to all existing Cargo config files, from the most to the least specific.
+fn config_file_paths() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = env::current_dir().ok().into_iter()
+        .flat_map(|cwd| cwd.ancestors().map(|d| d.join(".cargo")).collect::<Vec<_>>())
+        .collect();
+    if let Some(home) = cargo_home() {
+        if !dirs.contains(&home) {
+            dirs.push(home);
+        }
+    }
+    dirs.into_iter().filter_map(|dir| config_file_in(&dir)).collect()
+}
+
+/// Find the config file in given directory.
+/// As in Cargo, the legacy extension-less name takes precedence.
+fn config_file_in(dir: &Path) -> Option<PathBuf> {
+    ["config", "config.toml"].iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Find Cargo's credentials file in given directory (which should be `$CARGO_HOME`).
+/// As with config files, the legacy extension-less name takes precedence.
+fn credentials_file_in(dir: &Path) -> Option<PathBuf> {
+    ["credentials", "credentials.toml"].iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+
+/// Error that can occur while loading Cargo configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Error reading a config file.
+    Io(PathBuf, io::Error),
+    /// Error parsing a config file as TOML.
+    Parse(PathBuf, ::toml::de::Error),
+    /// Config key has a value of the wrong type.
+    Value(String, String),
+}
+impl Error for ConfigError {
+    fn description(&self) -> &str { "invalid Cargo configuration" }
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            ConfigError::Io(_, ref e) => Some(e),
+            ConfigError::Parse(_, ref e) => Some(e),
+            ConfigError::Value(..) => None,
+        }
+    }
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref p, ref e) =>
+                write!(fmt, "cannot read config file {}: {}", p.display(), e),
+            ConfigError::Parse(ref p, ref e) =>
+                write!(fmt, "malformed config file {}: {}", p.display(), e),
+            ConfigError::Value(ref k, ref v) => write!(fmt, "invalid value of `{}`: {}", k, v),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::{Config, env_var_name};
+
+    #[test]
+    fn env_var_names() {
+        assert_eq!("CARGO_REGISTRIES_MY_REGISTRY_INDEX",
+            env_var_name(&["registries", "my-registry", "index"]));
+        assert_eq!("CARGO_HTTP_PROXY", env_var_name(&["http", "proxy"]));
+    }
+
+    #[test]
+    fn debug_without_contents() {
+        let config = Config::from_toml("[registries.foo]\ntoken = \"secret\"");
+        assert_eq!(r#"Config { files: ["config.toml"] }"#, format!("{:?}", config));
+    }
+
+    #[test]
+    fn precedence() {
+        let config = Config{files: vec![
+            ("a".into(), "[registries.foo]\nindex = \"first\"".parse().unwrap()),
+            ("b".into(), "[registries.foo]\nindex = \"second\"\n[registries.bar]\nindex = \"third\"".parse().unwrap()),
+        ]};
+        assert_eq!(Some("first"), config.get(&["registries", "foo", "index"]).and_then(|v| v.as_str()));
+        assert_eq!(Some("third"), config.get(&["registries", "bar", "index"]).and_then(|v| v.as_str()));
+        assert!(config.get(&["registries", "baz", "index"]).is_none());
+    }
+
+    #[test]
+    fn typed_values() {
+        let config = Config{files: vec![
+            ("/home/user/.cargo/config.toml".into(), concat!(
+                "[test-http]\ntimeout = 60\ncheck-revoke = false\n",
+                "cainfo = \"certs/ca.pem\"\nuser-agent = 42\n",
+                "provider = \"my-provider --flag\"\nproviders = [\"a b\", \"c\"]\n",
+            ).parse().unwrap()),
+        ]};
+        assert_eq!(Some(60), config.get_int(&["test-http", "timeout"]).unwrap());
+        assert_eq!(Some(false), config.get_bool(&["test-http", "check-revoke"]).unwrap());
+        assert_eq!(None, config.get_bool(&["test-http", "missing"]).unwrap());
+        assert!(config.get_int(&["test-http", "check-revoke"]).is_err());
+        assert_eq!(Some(PathBuf::from("/home/user/certs/ca.pem")),
+            config.get_path(&["test-http", "cainfo"]));
+        assert_eq!(None, config.get_str(&["test-http", "user-agent"]));
+        assert_eq!(Some(vec!["my-provider".to_owned(), "--flag".to_owned()]),
+            config.get_args(&["test-http", "provider"]).unwrap());
+        assert_eq!(Some(vec!["a b".to_owned(), "c".to_owned()]),
+            config.get_args(&["test-http", "providers"]).unwrap());
+        assert!(config.get_args(&["test-http", "timeout"]).is_err());
+    }
+}
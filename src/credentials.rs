@@ -0,0 +1,340 @@
+//! Module for obtaining registry tokens from Cargo's credential providers
+//! (https://doc.rust-lang.org/cargo/reference/registry-authentication.html).
+//!
+//! Besides the built-in `cargo:token` (which reads Cargo's credentials)
+//! and `cargo:token-from-stdout`, providers can be external processes
+//! that speak Cargo's JSON-lines credential provider protocol
+//! (https://doc.rust-lang.org/cargo/reference/credential-provider-protocol.html).
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, Command, Stdio};
+
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use config::{Config, ConfigError};
+
+
+/// Provider used when none is configured.
+const DEFAULT_PROVIDER: &str = "cargo:token";
+
+/// Version of the credential provider protocol that we speak.
+const PROTOCOL_VERSION: u32 = 1;
+
+
+/// Get the token for the registry with given name and index URL
+/// from the credential providers configured for it.
+///
+/// Providers are tried in the order of their precedence, until one of them has a token.
+/// Returns `None` if none of them does.
+pub fn get_token(config: &Config, registry: &str, index: &str) -> Result<Option<String>, CredentialError> {
+    for provider in providers(config, registry)? {
+        let name = provider.join(" ");
+        trace!("Asking credential provider `{}` for the token of registry `{}`", name, registry);
+        let token = match provider[0].as_str() {
+            "cargo:token" => config.get_str(&["registries", registry, "token"]),
+            "cargo:token-from-stdout" => Some(token_from_stdout(&name, &provider[1..], registry, index)?),
+            builtin if builtin.starts_with("cargo:") => {
+                warn!("Credential provider `{}` is not supported, skipping it", builtin);
+                None
+            }
+            _ => run_provider(&name, &provider, registry, index)?,
+        };
+        match token {
+            Some(token) => {
+                debug!("Got the token of registry `{}` from credential provider `{}`", registry, name);
+                return Ok(Some(token));
+            }
+            None => trace!("Credential provider `{}` has no token for registry `{}`", name, registry),
+        }
+    }
+    Ok(None)
+}
+
+/// Credential providers of given registry (as commands with arguments),
+/// in the order they should be tried.
+fn providers(config: &Config, registry: &str) -> Result<Vec<Vec<String>>, CredentialError> {
+    let providers = match config.get_args(&["registries", registry, "credential-provider"])? {
+        Some(provider) => vec![provider],
+        None => {
+            // Global providers listed later take precedence.
+            let global = config.get_args(&["registry", "global-credential-providers"])?
+                .unwrap_or_else(|| vec![DEFAULT_PROVIDER.to_owned()]);
+            global.iter().rev()
+                .map(|p| p.split_whitespace().map(|s| s.to_owned()).collect::<Vec<_>>())
+                .filter(|p| !p.is_empty())
+                .collect()
+        }
+    };
+    providers.into_iter().map(|mut provider| {
+        // The command may be an alias defined in the [credential-alias] section.
+        if let Some(mut command) = config.get_args(&["credential-alias", &provider[0]])? {
+            command.extend(provider.drain(1..));
+            provider = command;
+        }
+        Ok(provider)
+    }).collect()
+}
+
+/// Get the token from the output of given command (the `cargo:token-from-stdout` provider).
+fn token_from_stdout(name: &str, command: &[String], registry: &str,
+                     index: &str) -> Result<String, CredentialError> {
+    let program = command.first()
+        .ok_or_else(|| CredentialError::Failed(name.to_owned(), "missing command".into()))?;
+    let output = Command::new(program).args(&command[1..])
+        .env("CARGO_REGISTRY_INDEX_URL", index)
+        .env("CARGO_REGISTRY_NAME_OPT", registry)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| CredentialError::Spawn(name.to_owned(), e))?;
+    if !output.status.success() {
+        return Err(CredentialError::Failed(name.to_owned(), output.status.to_string()));
+    }
+    let token = String::from_utf8(output.stdout)
+        .map_err(|_| CredentialError::Failed(name.to_owned(), "token is not valid UTF-8".into()))?;
+    let token = token.trim_end();
+    if token.is_empty() || token.contains(['\r', '\n']) {
+        return Err(CredentialError::Failed(name.to_owned(), "expected a single line with the token".into()));
+    }
+    Ok(token.to_owned())
+}
+
+/// Get the token from an external credential provider process.
+/// Returns `None` if the provider doesn't have the token for the registry.
+fn run_provider(name: &str, command: &[String], registry: &str,
+                index: &str) -> Result<Option<String>, CredentialError> {
+    let mut child = Command::new(&command[0]).args(&command[1..])
+        .arg("--cargo-plugin")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| CredentialError::Spawn(name.to_owned(), e))?;
+    let result = talk_to_provider(&mut child, registry, index);
+    // The provider should exit once its stdin is closed.
+    drop(child.stdin.take());
+    if result.is_err() {
+        let _ = child.kill();
+    }
+    let status = child.wait();
+    match result {
+        Ok(response) => match response {
+            Response::Ok(GetResponse{token}) => Ok(Some(token)),
+            Response::Err(ErrorResponse::NotFound) | Response::Err(ErrorResponse::UrlNotSupported) => Ok(None),
+            Response::Err(ErrorResponse::OperationNotSupported) =>
+                Err(CredentialError::Failed(name.to_owned(), "operation not supported".into())),
+            Response::Err(ErrorResponse::Other{message, caused_by}) => {
+                let message = message.unwrap_or_else(|| "unknown error".into());
+                let message = caused_by.iter().fold(message, |m, cause| format!("{}: {}", m, cause));
+                Err(CredentialError::Failed(name.to_owned(), message))
+            }
+        },
+        Err(message) => {
+            let message = match status {
+                Ok(status) if !status.success() => format!("{} ({})", message, status),
+                _ => message,
+            };
+            Err(CredentialError::Failed(name.to_owned(), message))
+        }
+    }
+}
+
+/// Exchange the protocol messages with a provider process to get the token.
+fn talk_to_provider(child: &mut Child, registry: &str, index: &str) -> Result<Response, String> {
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let hello: Hello = read_message(&mut stdout)?;
+    if !hello.v.contains(&PROTOCOL_VERSION) {
+        return Err(format!("unsupported protocol versions {:?}", hello.v));
+    }
+    let request = Request{
+        v: PROTOCOL_VERSION,
+        registry: RegistryInfo{index_url: index, name: registry},
+        kind: "get",
+        operation: "read",
+        args: vec![],
+    };
+    let mut line = serde_json::to_string(&request).map_err(|e| e.to_string())?;
+    line.push('\n');
+    let stdin = child.stdin.as_mut().unwrap();
+    stdin.write_all(line.as_bytes()).and_then(|_| stdin.flush())
+        .map_err(|e| format!("cannot send request: {}", e))?;
+    read_message(&mut stdout)
+}
+
+/// Read a single JSON line from the provider.
+fn read_message<T: DeserializeOwned, R: BufRead>(reader: &mut R) -> Result<T, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Err("exited without a response".into()),
+        Ok(_) => serde_json::from_str(&line).map_err(|e| format!("invalid response: {}", e)),
+        Err(e) => Err(format!("cannot read response: {}", e)),
+    }
+}
+
+
+// Messages of the credential provider protocol.
+
+/// First message from the provider, with the protocol versions it supports.
+#[derive(Debug, Deserialize)]
+struct Hello {
+    v: Vec<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct Request<'r> {
+    v: u32,
+    registry: RegistryInfo<'r>,
+    kind: &'r str,
+    operation: &'r str,
+    args: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RegistryInfo<'r> {
+    #[serde(rename = "index-url")]
+    index_url: &'r str,
+    name: &'r str,
+}
+
+#[derive(Debug, Deserialize)]
+enum Response {
+    Ok(GetResponse),
+    Err(ErrorResponse),
+}
+
+/// Successful response to the request for a token.
+/// Its other fields (about caching the token) are irrelevant to us.
+#[derive(Debug, Deserialize)]
+struct GetResponse {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+enum ErrorResponse {
+    UrlNotSupported,
+    NotFound,
+    OperationNotSupported,
+    Other {
+        #[serde(default)]
+        message: Option<String>,
+        #[serde(default, rename = "caused-by")]
+        caused_by: Vec<String>,
+    },
+}
+
+
+/// Error that can occur while obtaining a token from credential providers.
+#[derive(Debug)]
+pub enum CredentialError {
+    /// Configuration of the credential providers is invalid.
+    Config(ConfigError),
+    /// Credential provider of given name couldn't be run.
+    Spawn(String, io::Error),
+    /// Credential provider of given name failed, with given message.
+    Failed(String, String),
+}
+impl From<ConfigError> for CredentialError {
+    fn from(input: ConfigError) -> Self {
+        CredentialError::Config(input)
+    }
+}
+impl Error for CredentialError {
+    fn description(&self) -> &str { "credential provider error" }
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            CredentialError::Config(ref e) => Some(e),
+            CredentialError::Spawn(_, ref e) => Some(e),
+            CredentialError::Failed(..) => None,
+        }
+    }
+}
+impl fmt::Display for CredentialError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CredentialError::Config(ref e) => write!(fmt, "{}", e),
+            CredentialError::Spawn(ref n, ref e) =>
+                write!(fmt, "cannot run credential provider `{}`: {}", n, e),
+            CredentialError::Failed(ref n, ref m) =>
+                write!(fmt, "credential provider `{}` failed: {}", n, m),
+        }
+    }
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use tempfile;
+    use config::Config;
+    use super::{get_token, providers};
+
+    /// Write an executable shell script.
+    fn script(path: &Path, body: &str) {
+        fs::write(path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn provider_precedence() {
+        let config = Config::from_toml(concat!(
+            "[registry]\nglobal-credential-providers = [\"cargo:token\", \"my-provider --flag\"]\n",
+            "[registries.foo]\ncredential-provider = \"other\"\n",
+            "[credential-alias]\nother = [\"/usr/bin/other\", \"--from-alias\"]\n",
+        ));
+        assert_eq!(vec![vec!["/usr/bin/other", "--from-alias"]], providers(&config, "foo").unwrap());
+        assert_eq!(vec![vec!["my-provider", "--flag"], vec!["cargo:token"]], providers(&config, "bar").unwrap());
+        assert_eq!(vec![vec!["cargo:token"]], providers(&Config::default(), "bar").unwrap());
+    }
+
+    #[test]
+    fn token_from_stdout() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = dir.path().join("token.sh");
+        script(&command, r#"echo "token-for-$CARGO_REGISTRY_NAME_OPT""#);
+        let config = Config::from_toml(&format!(
+            "[registries.foo]\ncredential-provider = \"cargo:token-from-stdout {}\"\n", command.display()));
+        assert_eq!(Some("token-for-foo".to_owned()),
+            get_token(&config, "foo", "sparse+https://example.com/").unwrap());
+
+        script(&command, "exit 1");
+        assert!(get_token(&config, "foo", "sparse+https://example.com/").is_err());
+    }
+
+    #[test]
+    fn process_provider() {
+        let dir = tempfile::tempdir().unwrap();
+        let provider = dir.path().join("provider.sh");
+        let request = dir.path().join("request.json");
+        // Only has the token for one registry, and fails on others.
+        script(&provider, &format!(r#"
+            echo '{{"v":[1]}}'
+            read request
+            echo "$request" > {}
+            case "$request" in
+                *'"name":"foo"'*) echo '{{"Ok":{{"kind":"get","token":"secret","cache":"session","operation_independent":true}}}}' ;;
+                *'"name":"bar"'*) echo '{{"Err":{{"kind":"not-found"}}}}' ;;
+                *) echo '{{"Err":{{"kind":"other","message":"no way"}}}}' ;;
+            esac
+            read eof"#, request.display()));
+        let config = Config::from_toml(&format!(concat!(
+            "[registry]\nglobal-credential-providers = [\"cargo:token\", \"{}\"]\n",
+            "[registries.bar]\ntoken = \"static\"\n",
+        ), provider.display()));
+
+        assert_eq!(Some("secret".to_owned()), get_token(&config, "foo", "sparse+https://foo.example.com/").unwrap());
+        assert_eq!(concat!(
+            r#"{"v":1,"registry":{"index-url":"sparse+https://foo.example.com/","name":"foo"},"#,
+            r#""kind":"get","operation":"read","args":[]}"#, "\n",
+        ), fs::read_to_string(&request).unwrap());
+        // Falls back to the next provider if the token isn't found.
+        assert_eq!(Some("static".to_owned()), get_token(&config, "bar", "sparse+https://bar.example.com/").unwrap());
+        let error = get_token(&config, "baz", "sparse+https://baz.example.com/").unwrap_err();
+        assert!(error.to_string().ends_with("failed: no way"), "{}", error);
+    }
+}
@@ -0,0 +1,533 @@
+//! Module for resolving the dependency graph of crates.
+//!
+//! The resolution is simpler than Cargo's: each version requirement resolves
+//! to the newest matching release, and dependencies for all target platforms
+//! are included. This gives the set of crates a build *could* pull in,
+//! which is what matters for reviewing them.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+
+use args::Crate;
+use http::Client;
+use index::{self, Candidates, IndexError, Release, VersionSource};
+use registry::{Registry, RegistryError};
+
+
+/// Which kinds of dependencies to follow, besides the normal ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DepKinds {
+    /// Whether to follow build dependencies.
+    pub build: bool,
+    /// Whether to follow dev dependencies (of the root crates only, like Cargo does).
+    pub dev: bool,
+}
+
+/// Kind of a dependency.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DepKind {
+    Normal,
+    Build,
+    Dev,
+}
+impl DepKind {
+    fn from_index(kind: Option<&str>) -> Self {
+        match kind {
+            Some("build") => DepKind::Build,
+            Some("dev") => DepKind::Dev,
+            _ => DepKind::Normal,
+        }
+    }
+}
+impl fmt::Display for DepKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DepKind::Normal => write!(fmt, "normal"),
+            DepKind::Build => write!(fmt, "build"),
+            DepKind::Dev => write!(fmt, "dev"),
+        }
+    }
+}
+
+/// Features requested for the root crates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Features {
+    /// Explicitly requested features.
+    pub features: Vec<String>,
+    /// Whether all the features are enabled.
+    pub all: bool,
+    /// Whether the default features are enabled.
+    pub default: bool,
+}
+impl Default for Features {
+    fn default() -> Self {
+        Features{features: vec![], all: false, default: true}
+    }
+}
+
+
+/// Resolved dependency graph.
+#[derive(Clone, Debug)]
+pub struct Graph {
+    nodes: Vec<Node>,
+    roots: Vec<usize>,
+}
+
+impl Graph {
+    #[inline]
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    #[inline]
+    pub fn node(&self, id: usize) -> &Node {
+        &self.nodes[id]
+    }
+
+    /// Node IDs of the root crates, i.e. the ones whose dependencies were resolved.
+    #[inline]
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+}
+
+/// Single crate release in the dependency graph.
+#[derive(Clone, Debug)]
+pub struct Node {
+    /// Registry the crate comes from.
+    pub registry: Registry,
+    pub release: Release,
+    /// Features enabled on the crate.
+    pub features: BTreeSet<String>,
+    /// Whether the default features of the crate are enabled.
+    pub default_features: bool,
+    /// Dependencies of the crate, as node IDs, in the order of declaration.
+    pub deps: Vec<(usize, DepKind)>,
+}
+
+
+/// Resolve the dependency closure of given root crates.
+pub fn resolve(client: Option<&Client>, registry: &Registry, roots: &[Crate],
+               features: &Features, kinds: DepKinds,
+               candidates: Candidates) -> Result<Graph, ResolveError> {
+    let mut resolver = Resolver{
+        client, kinds, candidates,
+        registries: HashMap::new(),
+        releases: HashMap::new(),
+        nodes: vec![],
+        node_ids: HashMap::new(),
+        queue: VecDeque::new(),
+    };
+    let mut root_ids = vec![];
+    for crate_ in roots {
+        let id = resolver.add_root(registry, crate_, features)?;
+        if !root_ids.contains(&id) {
+            root_ids.push(id);
+        }
+    }
+    // Like Cargo, reject features that none of the root crates defines.
+    let unknown: Vec<_> = features.features.iter()
+        .filter(|f| !root_ids.iter().any(|&id| has_feature(&resolver.nodes[id].release, f)))
+        .cloned().collect();
+    if !unknown.is_empty() {
+        return Err(ResolveError::Features(unknown));
+    }
+    resolver.run(&root_ids)?;
+    debug!("Resolved {} crate(s) in the dependency graph", resolver.nodes.len());
+    Ok(Graph{nodes: resolver.nodes, roots: root_ids})
+}
+
+
+struct Resolver<'c> {
+    client: Option<&'c Client>,
+    kinds: DepKinds,
+    candidates: Candidates,
+    /// Registries of dependencies from outside of their dependent's registry,
+    /// by index URL.
+    registries: HashMap<String, Registry>,
+    /// Releases of crates fetched so far, by registry and crate name.
+    releases: HashMap<(String, String), Vec<Release>>,
+    nodes: Vec<Node>,
+    /// IDs of the nodes, by registry, crate name and version.
+    node_ids: HashMap<(String, String, String), usize>,
+    /// Nodes whose dependencies need to be (re)computed.
+    queue: VecDeque<usize>,
+}
+
+impl<'c> Resolver<'c> {
+    fn add_root(&mut self, registry: &Registry, crate_: &Crate,
+                features: &Features) -> Result<usize, ResolveError> {
+        let candidates = self.candidates;
+        let releases = self.releases(registry, crate_.name())?;
+        let release = match crate_.exact_version() {
+            Some(version) => releases.iter().find(|r| &r.vers == version)
+                .ok_or_else(|| ResolveError::Release{
+                    crate_: crate_.to_string(),
+                    error: Box::new(IndexError::NoMatch{yanked: 0, prerelease: None}),
+                })?,
+            None => index::resolve(releases, &crate_.version_requirement(), candidates)
+                .map_err(|error| ResolveError::Release{crate_: crate_.to_string(), error: Box::new(error)})?,
+        }.clone();
+
+        let mut requested: BTreeSet<String> = features.features.iter().cloned().collect();
+        if features.all {
+            requested.extend(release.features.keys().cloned());
+            requested.extend(release.deps.iter().filter(|d| d.optional).map(|d| d.name.clone()));
+        }
+        Ok(self.add_node(registry, release, requested, features.default))
+    }
+
+    /// Process the queued nodes until all their dependencies are resolved.
+    fn run(&mut self, roots: &[usize]) -> Result<(), ResolveError> {
+        while let Some(id) = self.queue.pop_front() {
+            let (registry, release, features, default_features) = {
+                let node = &self.nodes[id];
+                (node.registry.clone(), node.release.clone(),
+                 node.features.clone(), node.default_features)
+            };
+            let is_root = roots.contains(&id);
+            trace!("Resolving dependencies of crate `{}=={}`", release.name, release.vers);
+
+            let activated = activated_deps(&release, &features, default_features);
+            let mut deps = vec![];
+            for dep in &release.deps {
+                let kind = DepKind::from_index(dep.kind.as_deref());
+                let followed = match kind {
+                    DepKind::Normal => true,
+                    DepKind::Build => self.kinds.build,
+                    DepKind::Dev => self.kinds.dev && is_root,
+                };
+                let dep_features = match activated.get(&dep.name) {
+                    Some(f) if followed => f,
+                    _ => continue,
+                };
+
+                let dep_registry = match dep.registry {
+                    Some(ref index) if !registry.has_index(index) => self.registry(index)?,
+                    _ => registry.clone(),
+                };
+                let name = dep.package.as_ref().unwrap_or(&dep.name);
+                let candidates = self.candidates;
+                let dep_release = {
+                    let releases = self.releases(&dep_registry, name)?;
+                    index::resolve(releases, &dep.req, candidates)
+                        .map_err(|error| ResolveError::Dependency{
+                            crate_: format!("{}={}", name, dep.req),
+                            dependent: format!("{}=={}", release.name, release.vers),
+                            error: Box::new(error),
+                        })?
+                        .clone()
+                };
+                let mut requested: BTreeSet<String> = dep.features.iter().cloned().collect();
+                requested.extend(dep_features.iter().cloned());
+                let dep_id = self.add_node(
+                    &dep_registry, dep_release, requested, dep.default_features);
+                if !deps.contains(&(dep_id, kind)) {
+                    deps.push((dep_id, kind));
+                }
+            }
+            self.nodes[id].deps = deps;
+        }
+        Ok(())
+    }
+
+    /// Add the release to the graph, or enable more features on its existing node.
+    /// The node is queued for processing if it's new or its features have changed.
+    fn add_node(&mut self, registry: &Registry, release: Release,
+                features: BTreeSet<String>, default_features: bool) -> usize {
+        let key = (registry.name().to_owned(), release.name.clone(), release.vers.to_string());
+        if let Some(&id) = self.node_ids.get(&key) {
+            let node = &mut self.nodes[id];
+            let changed = !features.is_subset(&node.features)
+                || (default_features && !node.default_features);
+            if changed {
+                node.features.extend(features);
+                node.default_features |= default_features;
+                self.queue.push_back(id);
+            }
+            return id;
+        }
+        let id = self.nodes.len();
+        self.nodes.push(Node{
+            registry: registry.clone(), release, features, default_features, deps: vec![],
+        });
+        self.node_ids.insert(key, id);
+        self.queue.push_back(id);
+        id
+    }
+
+    fn releases(&mut self, registry: &Registry,
+                name: &str) -> Result<&Vec<Release>, ResolveError> {
+        let key = (registry.name().to_owned(), name.to_lowercase());
+        if !self.releases.contains_key(&key) {
+            let releases = index::get_releases(self.client, registry, name, VersionSource::Index)
+                .map_err(|error| ResolveError::Release{crate_: name.to_owned(), error: Box::new(error)})?;
+            self.releases.insert(key.clone(), releases);
+        }
+        Ok(&self.releases[&key])
+    }
+
+    fn registry(&mut self, index: &str) -> Result<Registry, ResolveError> {
+        if let Some(registry) = self.registries.get(index) {
+            return Ok(registry.clone());
+        }
+        let registry = Registry::from_source(self.client, index)?;
+        self.registries.insert(index.to_owned(), registry.clone());
+        Ok(registry)
+    }
+}
+
+
+/// Whether given feature can be requested for a release:
+/// either one it defines, an optional dependency, or a feature of a dependency.
+fn has_feature(release: &Release, feature: &str) -> bool {
+    if feature == "default" || release.features.contains_key(feature) {
+        return true;
+    }
+    match feature.find('/') {
+        Some(slash) => {
+            let dep = feature[..slash].trim_end_matches('?');
+            release.deps.iter().any(|d| d.name == dep)
+        }
+        None => {
+            let dep = feature.strip_prefix("dep:").unwrap_or(feature);
+            release.deps.iter().any(|d| d.optional && d.name == dep)
+        }
+    }
+}
+
+/// Determine which dependencies of a release are activated by given features,
+/// and which features should be requested for each of them.
+///
+/// Non-optional dependencies are always activated.
+/// Returned map is keyed by the (possibly renamed) dependency names.
+fn activated_deps(release: &Release, features: &BTreeSet<String>,
+                  default_features: bool) -> BTreeMap<String, BTreeSet<String>> {
+    let mut pending: Vec<String> = features.iter().cloned().collect();
+    if default_features && release.features.contains_key("default") {
+        pending.push("default".into());
+    }
+
+    let mut enabled = BTreeSet::new();
+    let mut optional_deps = BTreeSet::new();
+    let mut dep_features: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let mut weak_dep_features = vec![];
+    while let Some(feature) = pending.pop() {
+        if let Some(dep) = feature.strip_prefix("dep:") {
+            optional_deps.insert(dep.to_owned());
+        } else if let Some(slash) = feature.find('/') {
+            let (dep, dep_feature) = (&feature[..slash], &feature[slash + 1..]);
+            match dep.strip_suffix('?') {
+                Some(dep) => weak_dep_features.push((dep.to_owned(), dep_feature.to_owned())),
+                None => {
+                    // "foo/bar" also enables the optional dependency foo,
+                    // along with its implicit feature, if there is one.
+                    pending.push(dep.to_owned());
+                    dep_features.entry(dep.to_owned()).or_default()
+                        .insert(dep_feature.to_owned());
+                }
+            }
+        } else if enabled.insert(feature.clone()) {
+            match release.features.get(&feature) {
+                Some(implied) => pending.extend(implied.iter().cloned()),
+                // Optional dependencies are features by themselves, unless they're
+                // only referred to with the "dep:" syntax (which we don't tell apart).
+                None => { optional_deps.insert(feature); }
+            }
+        }
+    }
+
+    let mut activated: BTreeMap<String, BTreeSet<String>> = release.deps.iter()
+        .filter(|d| !d.optional || optional_deps.contains(&d.name))
+        .map(|d| (d.name.clone(), BTreeSet::new()))
+        .collect();
+    // Features of dependencies that aren't activated are dropped,
+    // which is exactly the semantics of the weak "foo?/bar" syntax.
+    for (dep, feature) in dep_features.into_iter()
+            .flat_map(|(d, fs)| fs.into_iter().map(move |f| (d.clone(), f)))
+            .chain(weak_dep_features) {
+        if let Some(features) = activated.get_mut(&dep) {
+            features.insert(feature);
+        }
+    }
+    activated
+}
+
+
+/// Error that can occur while resolving the dependency graph.
+#[derive(Debug)]
+pub enum ResolveError {
+    /// Error setting up the registry of a dependency.
+    Registry(RegistryError),
+    /// Error finding the release of a crate.
+    Release{crate_: String, error: Box<IndexError>},
+    /// Error finding the release of a dependency of given crate.
+    Dependency{crate_: String, dependent: String, error: Box<IndexError>},
+    /// Requested features that none of the root crates defines.
+    Features(Vec<String>),
+}
+impl From<RegistryError> for ResolveError {
+    fn from(input: RegistryError) -> Self {
+        ResolveError::Registry(input)
+    }
+}
+impl Error for ResolveError {
+    fn description(&self) -> &str { "failed to resolve dependencies" }
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            ResolveError::Registry(ref e) => Some(e),
+            ResolveError::Release{ref error, ..} => Some(&**error),
+            ResolveError::Dependency{ref error, ..} => Some(&**error),
+            ResolveError::Features(_) => None,
+        }
+    }
+}
+impl fmt::Display for ResolveError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResolveError::Registry(ref e) => write!(fmt, "{}", e),
+            ResolveError::Release{ref crate_, ref error} =>
+                write!(fmt, "cannot resolve crate `{}`: {}", crate_, error),
+            ResolveError::Dependency{ref crate_, ref dependent, ref error} =>
+                write!(fmt, "cannot resolve dependency `{}` of crate `{}`: {}",
+                    crate_, dependent, error),
+            ResolveError::Features(ref features) =>
+                write!(fmt, "none of the crates contains these features: {}", features.join(", ")),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    use serde_json;
+    use tempfile;
+
+    use args::Crate;
+    use index::{Candidates, Release};
+    use list::print_tree;
+    use registry::Registry;
+    use test_server::client;
+    use super::{activated_deps, resolve, DepKind, DepKinds, Features, ResolveError};
+
+    fn release(json: &str) -> Release {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn set(items: &[&str]) -> BTreeSet<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn feature_activation() {
+        let release = release(r#"{"name": "foo", "vers": "1.0.0", "cksum": "", "deps": [
+            {"name": "a", "req": "^1", "optional": false},
+            {"name": "b", "req": "^1", "optional": true},
+            {"name": "c", "req": "^1", "optional": true},
+            {"name": "d", "req": "^1", "optional": true}
+        ], "features": {
+            "default": ["std"],
+            "std": ["a/std", "c?/std"],
+            "extra": ["dep:b", "c"],
+            "d-serde": ["d/serde"]
+        }}"#);
+
+        let activated = activated_deps(&release, &set(&[]), false);
+        assert_eq!(vec!["a"], activated.keys().collect::<Vec<_>>());
+        assert!(activated["a"].is_empty());
+
+        let activated = activated_deps(&release, &set(&[]), true);
+        assert_eq!(set(&["std"]), activated["a"]);
+        assert!(!activated.contains_key("c"));
+
+        let activated = activated_deps(&release, &set(&["extra"]), true);
+        assert_eq!(vec!["a", "b", "c"], activated.keys().collect::<Vec<_>>());
+        assert_eq!(set(&["std"]), activated["c"]);
+
+        let activated = activated_deps(&release, &set(&["d-serde"]), false);
+        assert_eq!(set(&["serde"]), activated["d"]);
+    }
+
+    #[test]
+    fn dependency_closure() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = dir.path();
+        fs::write(index.join("config.json"), r#"{"dl": "http://localhost/dl"}"#).unwrap();
+        let files = [
+            ("3/a/app", vec![
+                r#"{"name": "app", "vers": "1.0.0", "cksum": "a", "deps": [
+                    {"name": "lib", "req": "^0.1"},
+                    {"name": "cc", "req": "^1", "kind": "build"},
+                    {"name": "tester", "req": "^1", "kind": "dev"}
+                ], "features": {"fancy": ["lib/fancy"]}}"#,
+            ]),
+            ("3/l/lib", vec![
+                r#"{"name": "lib", "vers": "0.1.0", "cksum": "l0", "deps": []}"#,
+                r#"{"name": "lib", "vers": "0.1.5", "cksum": "l1", "deps": [
+                    {"name": "cc", "req": "^1", "kind": "build"},
+                    {"name": "colors", "req": "^2", "optional": true}
+                ], "features": {"fancy": ["colors"]}}"#,
+                r#"{"name": "lib", "vers": "0.2.0", "cksum": "l2", "deps": []}"#,
+            ]),
+            ("2/cc", vec![r#"{"name": "cc", "vers": "1.0.0", "cksum": "c", "deps": []}"#]),
+            ("co/lo/colors", vec![r#"{"name": "colors", "vers": "2.1.0", "cksum": "s", "deps": []}"#]),
+            ("te/st/tester", vec![r#"{"name": "tester", "vers": "1.0.0", "cksum": "t", "deps": []}"#]),
+        ];
+        for &(path, ref lines) in &files {
+            let path = index.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let lines: Vec<_> = lines.iter().map(|l| l.replace('\n', " ")).collect();
+            fs::write(path, lines.join("\n")).unwrap();
+        }
+
+        let client = client(0);
+        let registry = Registry::from_index(
+            Some(&client), "test", &format!("file://{}", index.display())).unwrap();
+        let roots = ["app".parse::<Crate>().unwrap()];
+        let names = |graph: &super::Graph| graph.nodes().iter()
+            .map(|n| format!("{}=={}", n.release.name, n.release.vers))
+            .collect::<Vec<_>>();
+
+        let graph = resolve(Some(&client), &registry, &roots, &Features::default(),
+                            DepKinds::default(), Candidates::default()).unwrap();
+        assert_eq!(vec!["app==1.0.0", "lib==0.1.5"], names(&graph));
+        assert_eq!(vec![(1, DepKind::Normal)], graph.node(graph.roots()[0]).deps);
+
+        let features = Features{features: vec!["fancy".into()], ..Features::default()};
+        let kinds = DepKinds{build: true, dev: true};
+        let graph = resolve(Some(&client), &registry, &roots, &features,
+                            kinds, Candidates::default()).unwrap();
+        assert_eq!(vec!["app==1.0.0", "lib==0.1.5", "cc==1.0.0", "tester==1.0.0", "colors==2.1.0"],
+            names(&graph));
+        assert_eq!(vec![(2, DepKind::Build), (4, DepKind::Normal)], graph.node(1).deps);
+        assert_eq!(set(&["fancy"]), graph.node(1).features);
+
+        let mut tree = vec![];
+        print_tree(&mut tree, &graph).unwrap();
+        assert_eq!(concat!(
+            "app v1.0.0\n",
+            "├── lib v0.1.5\n",
+            "│   ├── cc v1.0.0 (build)\n",
+            "│   └── colors v2.1.0\n",
+            "├── cc v1.0.0 (build)\n",
+            "└── tester v1.0.0 (dev)\n",
+        ), String::from_utf8(tree).unwrap());
+        for requested in &[vec!["lib/fancy"], vec!["default"]] {
+            let features = Features{features: requested.iter().map(|f| f.to_string()).collect(),
+                                    ..Features::default()};
+            assert!(resolve(Some(&client), &registry, &roots, &features,
+                            DepKinds::default(), Candidates::default()).is_ok());
+        }
+        let features = Features{features: vec!["fancy".into(), "shiny".into(), "nope/x".into()],
+                                ..Features::default()};
+        match resolve(Some(&client), &registry, &roots, &features,
+                      DepKinds::default(), Candidates::default()) {
+            Err(ResolveError::Features(ref unknown)) => assert_eq!(&["shiny", "nope/x"], &unknown[..]),
+            r => panic!("unexpected result: {:?}", r.map(|g| names(&g))),
+        }
+    }
+}
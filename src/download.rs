@@ -0,0 +1,946 @@
+//! Module for downloading crate archives.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use hex;
+use reqwest::{self, Response};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, RANGE};
+use semver::Version;
+use serde_json;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+use cache::{find_cargo_archive, Cache, CacheWriter};
+use http::{Client, Token};
+use progress::Tracker;
+use registry::{Registry, RegistryError};
+
+
+/// Local places where crate archives are looked for before downloading them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalArchives<'l> {
+    /// Our own download cache, which downloaded archives are also added to.
+    pub cache: Option<&'l Cache>,
+    /// Cargo's cache of the archives it has downloaded (usually `$CARGO_HOME/registry/cache`),
+    /// which is only read from.
+    pub cargo_cache: Option<&'l Path>,
+    /// Partial download of the archive to resume, if it's there,
+    /// and where the archive is written to as it's downloaded (until it's finished).
+    pub partial: Option<&'l Path>,
+}
+
+/// Start downloading given crate.
+///
+/// The gzipped archive can then be read from the returned `Download`
+/// as it arrives. If `verify` is true, the archive will be checked against
+/// the expected SHA256 `checksum`, which must then be provided.
+///
+/// If the checksum is known, the `local` archives are looked up first,
+/// and the download doesn't need any HTTP request if the crate is found there.
+/// Otherwise, it's added to the cache (if any) as it's downloaded.
+/// Without a `client` (i.e. in offline mode), only the local archives are used.
+///
+/// Downloads that get interrupted are resumed with range requests, if the server supports them.
+pub fn download_crate(client: Option<&Client>, registry: &Registry, name: &str, version: &Version,
+                      checksum: Option<&str>, verify: bool,
+                      local: LocalArchives) -> Result<Download, DownloadError> {
+    if verify && checksum.is_none() {
+        return Err(DownloadError::NoChecksum);
+    }
+    let download_url = registry.download_url(name, version, checksum)?;
+    if let Some(checksum) = checksum {
+        if let Some((file, origin)) = local.open(registry, name, version, checksum) {
+            return Ok(Download{
+                url: download_url,
+                name: name.to_owned(),
+                version: version.clone(),
+                // Local archives are always verified, since they're looked up by checksum.
+                expected: Some(checksum.to_owned()),
+                replay: None,
+                source: Box::new(file),
+                origin,
+                part: None,
+                resume: None,
+                progress: None,
+                cache_writer: None,
+                hasher: Sha256::new(),
+                size: 0,
+            });
+        }
+    }
+    let client = client.ok_or(DownloadError::Offline)?;
+    debug!("Downloading crate `{}=={}` from {}", name, version, download_url);
+    let mut transfer = match local.partial.and_then(|p| PartFile::previous(p, &download_url)) {
+        Some((part, info)) => resume_download(client, registry.token(), &download_url, part, info)?,
+        None => Transfer::start(client, registry.token(), &download_url)?,
+    };
+    if let Some(path) = local.partial.filter(|_| transfer.part.is_none()) {
+        transfer.part = PartFile::create(path).map_err(|e| warn!(
+            "Can't keep the partial download of crate `{}=={}` in {}: {}",
+            name, version, path.display(), e)).ok();
+    }
+    if let Some(ref part) = transfer.part {
+        if let Err(e) = part.write_info(&download_url, &transfer.resume) {
+            debug!("Can't record the partial download in {}: {}", part.path.display(), e);
+        }
+    }
+
+    let cache_writer = local.cache.and_then(|c| c.writer(registry, name, version)
+        .map_err(|e| warn!("Can't add crate `{}=={}` to the cache in {}: {}",
+            name, version, c.dir().display(), e))
+        .ok());
+    let progress = Tracker::start(name, &version.to_string(), transfer.resume.length);
+    Ok(Download{
+        url: download_url,
+        name: name.to_owned(),
+        version: version.clone(),
+        expected: if verify { checksum.map(|c| c.to_owned()) } else { None },
+        replay: transfer.replay,
+        source: transfer.source,
+        origin: Origin::Network,
+        part: transfer.part,
+        resume: Some(transfer.resume),
+        progress,
+        cache_writer,
+        hasher: Sha256::new(),
+        size: 0,
+    })
+}
+
+/// Resume the download of an archive whose first part has been downloaded before.
+fn resume_download(client: &Client, token: Option<&Token>, url: &str, part: PartFile,
+                   info: PartInfo) -> Result<Transfer, DownloadError> {
+    let offset = part.file.metadata()?.len();
+    let mut resume = Resume{
+        client: client.clone(),
+        token: token.cloned(),
+        etag: info.etag,
+        length: info.length,
+        attempts: 0,
+    };
+    // The whole archive might have been downloaded already (just not verified).
+    if resume.length == Some(offset) {
+        debug!("Archive has been downloaded to {} before", part.path.display());
+        let replay = Some(File::open(&part.path)?);
+        return Ok(Transfer{replay, source: Box::new(io::empty()), part: Some(part), resume});
+    }
+    let (response, resumed) = resume.request_rest(url, offset)?;
+    let replay = if resumed {
+        info!("Resuming the download from {} ({} bytes downloaded before)",
+            part.path.display(), offset);
+        Some(File::open(&part.path)?)
+    } else {
+        debug!("Download cannot be resumed from {}, starting over", part.path.display());
+        part.file.set_len(0)?;
+        None
+    };
+    Ok(Transfer{replay, source: Box::new(response), part: Some(part), resume})
+}
+
+/// Source of an archive that's being downloaded.
+struct Transfer {
+    /// Part of the archive downloaded before, which is read first.
+    replay: Option<File>,
+    /// HTTP response with the (rest of the) archive.
+    source: Box<dyn Read>,
+    part: Option<PartFile>,
+    resume: Resume,
+}
+
+impl Transfer {
+    /// Start downloading the whole archive.
+    fn start(client: &Client, token: Option<&Token>, url: &str) -> Result<Self, DownloadError> {
+        let response = check_status(url, client.get(url, token)?)?;
+        let resume = Resume{
+            client: client.clone(),
+            token: token.cloned(),
+            etag: strong_etag(&response),
+            length: content_length(&response),
+            attempts: 0,
+        };
+        trace!("Download size: {}", resume.length.map_or("<unknown>".into(), |cl| format!("{} bytes", cl)));
+        Ok(Transfer{replay: None, source: Box::new(response), part: None, resume})
+    }
+}
+
+/// What's needed to resume a download if it gets interrupted.
+#[derive(Clone, Debug)]
+struct Resume {
+    client: Client,
+    /// Token of the registry that the archive is downloaded from.
+    token: Option<Token>,
+    /// ETag of the archive, which the range requests are conditional on.
+    etag: Option<String>,
+    /// Total size of the archive, if known.
+    length: Option<u64>,
+    /// Number of times the download has been resumed already.
+    attempts: u32,
+}
+
+impl Resume {
+    /// Request the rest of the archive from given offset.
+    ///
+    /// If the server doesn't respond with the requested range (e.g. because it
+    /// doesn't support range requests or the archive has changed), the response
+    /// has the whole archive instead, which is indicated by returning false.
+    fn request_rest(&mut self, url: &str, offset: u64) -> Result<(Response, bool), DownloadError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, HeaderValue::from_str(&format!("bytes={}-", offset)).unwrap());
+        if let Some(value) = self.etag.as_ref().and_then(|e| HeaderValue::from_str(e).ok()) {
+            headers.insert(IF_RANGE, value);
+        }
+        let response = self.client.get_with(url, headers, self.token.as_ref())?;
+        let response = match response.status().as_u16() {
+            206 => {
+                let range = response.headers().get(CONTENT_RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(parse_content_range);
+                match range {
+                    Some((start, total)) if start == offset
+                        && (self.length.is_none() || total == self.length) => {
+                        return Ok((response, true));
+                    }
+                    _ => {
+                        debug!("Unexpected Content-Range of the resumed download: {:?}",
+                            response.headers().get(CONTENT_RANGE));
+                        check_status(url, self.request_all(url)?)?
+                    }
+                }
+            }
+            // The range isn't satisfiable if the archive has gotten shorter.
+            416 => check_status(url, self.request_all(url)?)?,
+            _ => check_status(url, response)?,
+        };
+        self.etag = strong_etag(&response);
+        self.length = content_length(&response);
+        Ok((response, false))
+    }
+
+    /// Request the whole archive again.
+    fn request_all(&self, url: &str) -> reqwest::Result<Response> {
+        self.client.get(url, self.token.as_ref())
+    }
+}
+
+/// Check that the response to a download request is successful.
+fn check_status(url: &str, response: Response) -> Result<Response, DownloadError> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(DownloadError::Status(url.to_owned(), response.status().as_u16()))
+    }
+}
+
+fn content_length(response: &Response) -> Option<u64> {
+    response.headers().get(CONTENT_LENGTH)
+        .and_then(|ct_len| ct_len.to_str().ok())
+        .and_then(|ct_len| ct_len.parse().ok())
+}
+
+/// Strong ETag of the response, if it has one (weak ones cannot be used with `If-Range`).
+fn strong_etag(response: &Response) -> Option<String> {
+    response.headers().get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+        .map(|etag| etag.to_owned())
+}
+
+/// Parse the value of a `Content-Range` header, like "bytes 100-199/1000",
+/// into the start of the range and the total size (if known).
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let start = range.split_once('-')?.0.parse().ok()?;
+    let total = if total == "*" { None } else { Some(total.parse().ok()?) };
+    Some((start, total))
+}
+
+
+/// File that an archive is written to as it's downloaded,
+/// so that the download can be resumed later if it doesn't finish.
+///
+/// Next to it, a JSON file records where the archive is downloaded from,
+/// to check that the download is resumed from the same place.
+#[derive(Debug)]
+struct PartFile {
+    path: PathBuf,
+    file: File,
+}
+
+/// Information about a partial download that's stored next to it.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct PartInfo {
+    url: String,
+    etag: Option<String>,
+    length: Option<u64>,
+}
+
+impl PartFile {
+    /// Create a new, empty file for a partial download.
+    fn create(path: &Path) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(PartFile{path: path.to_owned(), file})
+    }
+
+    /// Open the partial download at given path, if it's there
+    /// and the archive has been downloaded from given URL.
+    fn previous(path: &Path, url: &str) -> Option<(Self, PartInfo)> {
+        let info: PartInfo = fs::read(info_path(path)).ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())?;
+        if info.url != url {
+            debug!("Ignoring partial download of {} in {}", info.url, path.display());
+            return None;
+        }
+        let file = fs::OpenOptions::new().append(true).open(path).ok()?;
+        if file.metadata().ok()?.len() == 0 {
+            return None;
+        }
+        Some((PartFile{path: path.to_owned(), file}, info))
+    }
+
+    fn write_info(&self, url: &str, resume: &Resume) -> io::Result<()> {
+        let info = PartInfo{url: url.to_owned(), etag: resume.etag.clone(), length: resume.length};
+        fs::write(info_path(&self.path), serde_json::to_vec(&info)?)
+    }
+
+    /// Empty the file to download the archive into it from scratch,
+    /// recording the new information about the archive.
+    fn restart(&mut self, url: &str, resume: &Resume) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_info(url, resume)
+    }
+
+    /// Move the file to given path once the download has finished,
+    /// removing the information next to it.
+    fn persist(self, path: &Path) -> io::Result<()> {
+        fs::rename(&self.path, path)?;
+        let info = info_path(&self.path);
+        if let Err(e) = fs::remove_file(&info) {
+            debug!("Can't remove {}: {}", info.display(), e);
+        }
+        Ok(())
+    }
+
+    /// Remove the file (once the download has finished).
+    fn remove(self) {
+        for path in &[info_path(&self.path), self.path] {
+            if let Err(e) = fs::remove_file(path) {
+                debug!("Can't remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Create a temporary file in the same directory as given path,
+/// so that it can be renamed to it.
+fn temp_file_next_to(path: &Path) -> io::Result<NamedTempFile> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    NamedTempFile::new_in(dir)
+}
+
+/// Path of the JSON file with information about the partial download at given path.
+fn info_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".json");
+    path.with_file_name(name)
+}
+
+impl<'l> LocalArchives<'l> {
+    /// Open the local archive of given crate with given checksum, if there is one.
+    fn open(&self, registry: &Registry, name: &str, version: &Version,
+            checksum: &str) -> Option<(File, Origin)> {
+        if let Some((file, path)) = self.cache.and_then(|c| c.open(registry, name, version, checksum)) {
+            debug!("Reading crate `{}=={}` from the cache: {}", name, version, path.display());
+            return Some((file, Origin::Cache(path)));
+        }
+        let path = self.cargo_cache
+            .and_then(|dir| find_cargo_archive(dir, registry, name, version, checksum))?;
+        debug!("Reading crate `{}=={}` from Cargo's cache: {}", name, version, path.display());
+        match File::open(&path) {
+            Ok(file) => Some((file, Origin::Cargo(path))),
+            Err(e) => {
+                debug!("Cannot open {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+/// Where a crate archive is read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Origin {
+    Network,
+    /// Our own download cache.
+    Cache(PathBuf),
+    /// Cargo's cache of downloaded archives.
+    Cargo(PathBuf),
+}
+
+
+/// Crate archive being downloaded (or read from the cache).
+///
+/// Reading from it yields the gzipped archive, whose checksum is computed
+/// on the fly. Once the caller is done, `finish` must be called to verify it.
+pub struct Download {
+    url: String,
+    name: String,
+    version: Version,
+    /// Checksum to verify the archive against, if any.
+    expected: Option<String>,
+    /// Partial download the archive is read from first, when resuming it.
+    replay: Option<File>,
+    /// HTTP response or local file the archive is read from.
+    source: Box<dyn Read>,
+    origin: Origin,
+    /// File the archive is written to as it's downloaded, until it's finished.
+    part: Option<PartFile>,
+    /// How to resume the download if it gets interrupted (unless it's read from a local file).
+    resume: Option<Resume>,
+    /// Progress of the download shown on the terminal, if any.
+    progress: Option<Tracker>,
+    /// Cache entry the archive is being written to as it's downloaded, if any.
+    cache_writer: Option<CacheWriter>,
+    hasher: Sha256,
+    /// Number of bytes read so far.
+    size: u64,
+}
+
+impl Download {
+    /// Read the rest of the archive (if any) and verify its checksum.
+    ///
+    /// Note that by then, the archive has already been passed to the reader,
+    /// so it's up to the caller to discard it if this fails.
+    pub fn finish(self) -> Result<Downloaded, DownloadError> {
+        self.finish_into(None)
+    }
+
+    /// Read the whole archive, verify its checksum, and only then move it to `path`,
+    /// so that nothing is left there if the download fails.
+    ///
+    /// The archive is kept in its partial download (see `LocalArchives::partial`)
+    /// until then, or in a temporary file next to `path` if there's none.
+    pub fn save(self, path: &Path) -> Result<Downloaded, DownloadError> {
+        self.finish_into(Some(path))
+    }
+
+    fn finish_into(mut self, path: Option<&Path>) -> Result<Downloaded, DownloadError> {
+        let mut temp = match path {
+            Some(path) if self.part.is_none() => Some(temp_file_next_to(path)
+                .map_err(|e| DownloadError::Save(path.to_owned(), e))?),
+            _ => None,
+        };
+        let mut buf = [0; 8192];
+        loop {
+            let count = self.read(&mut buf)?;
+            if count == 0 {
+                break;
+            }
+            if let (Some(path), Some(temp)) = (path, temp.as_mut()) {
+                temp.write_all(&buf[..count])
+                    .map_err(|e| DownloadError::Save(path.to_owned(), e))?;
+            }
+        }
+        let actual = hex::encode(self.hasher.result());
+        let verified = match self.expected {
+            Some(ref expected) => verify_checksum(expected, &actual),
+            None => Ok(()),
+        };
+        // A download that can't be verified shouldn't be resumed later either.
+        let mut part = self.part.take();
+        if path.is_none() || verified.is_err() {
+            if let Some(part) = part.take() {
+                part.remove();
+            }
+        }
+        if let Err(e) = verified {
+            if let Origin::Cache(ref path) = self.origin {
+                warn!("Removing corrupted archive from the cache: {}", path.display());
+                let _ = fs::remove_file(path);
+            }
+            return Err(e);
+        }
+        match self.expected {
+            Some(_) => debug!("Verified SHA256 checksum of crate `{}=={}`: {}",
+                self.name, self.version, actual),
+            None => debug!("SHA256 checksum of crate `{}=={}` (not verified): {}",
+                self.name, self.version, actual),
+        }
+        if let Some(path) = path {
+            let saved = match (part, temp) {
+                (Some(part), _) => part.persist(path),
+                (None, Some(temp)) => temp.persist(path).map(|_| ()).map_err(|e| e.error),
+                // Writing to the partial download has failed, and it's been given up on.
+                (None, None) => Err(io::Error::other("the partial download couldn't be written")),
+            };
+            saved.map_err(|e| DownloadError::Save(path.to_owned(), e))?;
+        }
+        match self.origin {
+            Origin::Network => info!("Crate `{}=={}` downloaded successfully ({} bytes)",
+                self.name, self.version, self.size),
+            Origin::Cache(_) => info!("Crate `{}=={}` read from the cache ({} bytes)",
+                self.name, self.version, self.size),
+            Origin::Cargo(_) => info!("Crate `{}=={}` read from Cargo's cache ({} bytes)",
+                self.name, self.version, self.size),
+        }
+        if let Some(writer) = self.cache_writer.take() {
+            match writer.persist(&actual) {
+                Ok(path) => debug!("Crate `{}=={}` added to the cache: {}",
+                    self.name, self.version, path.display()),
+                Err(e) => warn!("Can't add crate `{}=={}` to the cache: {}",
+                    self.name, self.version, e),
+            }
+        }
+        Ok(Downloaded{url: self.url, size: self.size, sha256: actual,
+            cached: self.origin != Origin::Network})
+    }
+}
+
+/// Summary of a finished download.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Downloaded {
+    /// URL the archive has been downloaded from.
+    pub url: String,
+    /// Size of the archive in bytes.
+    pub size: u64,
+    /// Actual SHA256 checksum of the archive, as a hex string.
+    pub sha256: String,
+    /// Whether the archive has been read from a local cache rather than downloaded.
+    pub cached: bool,
+}
+
+impl Download {
+    /// Read from the partial download first, then from the source,
+    /// resuming the download if the connection drops.
+    fn read_source(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(ref mut replay) = self.replay {
+            match replay.read(buf)? {
+                0 => self.replay = None,
+                count => return Ok(count),
+            }
+        }
+        loop {
+            let error = match self.source.read(buf) {
+                Ok(count) => {
+                    self.write_part(&buf[..count]);
+                    return Ok(count);
+                }
+                Err(e) => e,
+            };
+            self.reconnect(error)?;
+        }
+    }
+
+    /// Resume the download after given error, or return it if that isn't possible.
+    fn reconnect(&mut self, error: io::Error) -> io::Result<()> {
+        let resume = match self.resume {
+            Some(ref mut resume) if resume.attempts < resume.client.retries() => resume,
+            _ => return Err(error),
+        };
+        resume.attempts += 1;
+        warn!("Download of crate `{}=={}` interrupted after {} bytes ({}), resuming ({}/{})",
+            self.name, self.version, self.size, error, resume.attempts, resume.client.retries());
+        let (mut response, resumed) = resume.request_rest(&self.url, self.size)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        if !resumed {
+            // The partial download starts over, too, as the archive may have changed
+            // (and its new ETag and length are what a later resumption must rely on).
+            let restarted = match self.part {
+                Some(ref mut part) => part.restart(&self.url, resume),
+                None => Ok(()),
+            };
+            if let Err(e) = restarted {
+                warn!("Can't keep the partial download of crate `{}=={}`: {}",
+                    self.name, self.version, e);
+                self.part = None;
+            }
+            // The bytes we already have were passed on, so skip them in the whole archive.
+            debug!("Download cannot be resumed, skipping {} bytes of the whole archive", self.size);
+            let mut skipped = 0;
+            let mut buf = [0; 8192];
+            while skipped < self.size {
+                let len = buf.len().min((self.size - skipped) as usize);
+                let count = response.read(&mut buf[..len])?;
+                if count == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                        "archive is shorter than the part downloaded before"));
+                }
+                self.write_part(&buf[..count]);
+                skipped += count as u64;
+            }
+        }
+        self.source = Box::new(response);
+        Ok(())
+    }
+
+    /// Write the bytes to the partial download (if any),
+    /// giving up on keeping it if that fails.
+    fn write_part(&mut self, bytes: &[u8]) {
+        let written = self.part.as_mut().map(|p| p.file.write_all(bytes));
+        if let Some(Err(e)) = written {
+            warn!("Can't keep the partial download of crate `{}=={}`: {}",
+                self.name, self.version, e);
+            self.part = None;
+        }
+    }
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.read_source(buf)?;
+        self.hasher.input(&buf[..count]);
+        if let Some(ref progress) = self.progress {
+            progress.advance(count);
+        }
+        // Failing to cache the archive is no reason to fail the download.
+        let cached = self.cache_writer.as_mut().map(|w| w.write_all(&buf[..count]));
+        if let Some(Err(e)) = cached {
+            warn!("Can't add crate `{}=={}` to the cache: {}", self.name, self.version, e);
+            self.cache_writer = None;
+        }
+        self.size += count as u64;
+        Ok(count)
+    }
+}
+
+/// Check whether the actual checksum of a crate archive is the expected one.
+pub fn verify_checksum(expected: &str, actual: &str) -> Result<(), DownloadError> {
+    if expected.eq_ignore_ascii_case(actual) {
+        Ok(())
+    } else {
+        Err(DownloadError::ChecksumMismatch{
+            expected: expected.to_lowercase(),
+            actual: actual.to_owned(),
+        })
+    }
+}
+
+
+/// Error that can occur while downloading a crate.
+#[derive(Debug)]
+pub enum DownloadError {
+    /// Error determining the download URL.
+    Registry(RegistryError),
+    /// HTTP request returned an error status code.
+    Status(String, u16),
+    /// Error while making the HTTP request.
+    Http(reqwest::Error),
+    /// I/O error while reading the response.
+    Io(io::Error),
+    /// Checksum to verify the archive against is not known.
+    NoChecksum,
+    /// Archive isn't available locally, and downloading it isn't allowed.
+    Offline,
+    /// Downloaded archive doesn't have the expected checksum.
+    ChecksumMismatch{expected: String, actual: String},
+    /// Archive couldn't be written to given path.
+    Save(PathBuf, io::Error),
+}
+impl From<RegistryError> for DownloadError {
+    fn from(input: RegistryError) -> Self {
+        DownloadError::Registry(input)
+    }
+}
+impl From<reqwest::Error> for DownloadError {
+    fn from(input: reqwest::Error) -> Self {
+        DownloadError::Http(input)
+    }
+}
+impl From<io::Error> for DownloadError {
+    fn from(input: io::Error) -> Self {
+        DownloadError::Io(input)
+    }
+}
+impl Error for DownloadError {
+    fn description(&self) -> &str { "failed to download crate" }
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            DownloadError::Registry(ref e) => Some(e),
+            DownloadError::Http(ref e) => Some(e),
+            DownloadError::Io(ref e) | DownloadError::Save(_, ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for DownloadError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DownloadError::Registry(ref e) => write!(fmt, "{}", e),
+            DownloadError::Status(ref url, s) => write!(fmt, "HTTP status {} from {}", s, url),
+            DownloadError::Http(ref e) => write!(fmt, "HTTP error: {}", e),
+            DownloadError::Io(ref e) => write!(fmt, "I/O error: {}", e),
+            DownloadError::NoChecksum => write!(fmt, "no checksum to verify the crate against"),
+            DownloadError::Offline => write!(fmt, concat!(
+                "crate isn't available locally (in the download cache or Cargo's cache) ",
+                "and cannot be downloaded in offline mode")),
+            DownloadError::ChecksumMismatch{ref expected, ref actual} =>
+                write!(fmt, "checksum mismatch (expected SHA256 {}, got {})", expected, actual),
+            DownloadError::Save(ref p, ref e) =>
+                write!(fmt, "failed to write the archive to {}: {}", p.display(), e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use semver::Version;
+    use test_server::{Response, TestServer};
+    use std::io::Read;
+    use hex;
+    use sha2::{Digest, Sha256};
+    use tempfile;
+    use cache::Cache;
+    use serde_json;
+    use super::{download_crate, info_path, parse_content_range, verify_checksum,
+                DownloadError, LocalArchives, PartInfo};
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn sha256_hex(bytes: &[u8]) -> String {
+        hex::encode(Sha256::digest(bytes))
+    }
+
+    #[test]
+    fn sha256() {
+        assert_eq!(EMPTY_SHA256, sha256_hex(b""));
+    }
+
+    #[test]
+    fn checksums() {
+        assert!(verify_checksum(&EMPTY_SHA256.to_uppercase(), EMPTY_SHA256).is_ok());
+        match verify_checksum("abcd", EMPTY_SHA256) {
+            Err(DownloadError::ChecksumMismatch{..}) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn verified_download() {
+        let server = TestServer::serve(vec![
+            Response::ok(r#"{"dl": "$SERVER/dl/{crate}-{version}.crate"}"#),
+            Response::ok(""),
+            Response::ok("tampered"),
+            Response::new(404, ""),
+        ]);
+        let (client, registry) = server.registry(0);
+        let version = Version::parse("1.0.0").unwrap();
+
+        let mut download = download_crate(
+            Some(&client), &registry, "foo", &version, Some(EMPTY_SHA256), true, LocalArchives::default()).unwrap();
+        let mut bytes = vec![];
+        download.read_to_end(&mut bytes).unwrap();
+        assert!(bytes.is_empty());
+        let downloaded = download.finish().unwrap();
+        assert_eq!(EMPTY_SHA256, downloaded.sha256);
+        assert_eq!(0, downloaded.size);
+        assert_eq!(format!("{}/dl/foo-1.0.0.crate", server.url()), downloaded.url);
+
+        // Checksum is verified even if the archive hasn't been read.
+        let download = download_crate(
+            Some(&client), &registry, "foo", &version, Some(EMPTY_SHA256), true, LocalArchives::default()).unwrap();
+        match download.finish() {
+            Err(DownloadError::ChecksumMismatch{ref actual, ..}) =>
+                assert_eq!(&sha256_hex(b"tampered"), actual),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match download_crate(Some(&client), &registry, "foo", &version, None, false, LocalArchives::default()) {
+            Err(DownloadError::Status(_, 404)) => {}
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        match download_crate(Some(&client), &registry, "foo", &version, None, true, LocalArchives::default()) {
+            Err(DownloadError::NoChecksum) => {}
+            _ => panic!("expected missing checksum error"),
+        }
+        assert_eq!("/dl/foo-1.0.0.crate", server.requested_paths()[1]);
+    }
+
+    #[test]
+    fn cached_download() {
+        let server = TestServer::serve(vec![
+            Response::ok(r#"{"dl": "$SERVER/dl/{crate}-{version}.crate"}"#),
+            Response::ok("archive"),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path());
+        let (client, registry) = server.registry(0);
+        let version = Version::parse("1.0.0").unwrap();
+        let checksum = sha256_hex(b"archive");
+
+        for &cached in &[false, true] {
+            let mut download = download_crate(
+                Some(&client), &registry, "foo", &version, Some(&checksum), true,
+                LocalArchives{cache: Some(&cache), ..LocalArchives::default()}).unwrap();
+            let mut bytes = vec![];
+            download.read_to_end(&mut bytes).unwrap();
+            assert_eq!(b"archive".to_vec(), bytes);
+            let downloaded = download.finish().unwrap();
+            assert_eq!(cached, downloaded.cached);
+            assert_eq!(checksum, downloaded.sha256);
+        }
+        // The second time, the archive came from the cache.
+        assert_eq!(2, server.requested_paths().len());
+        assert_eq!(1, cache.entries().unwrap().len());
+    }
+
+    #[test]
+    fn content_ranges() {
+        assert_eq!(Some((100, Some(1000))), parse_content_range("bytes 100-999/1000"));
+        assert_eq!(Some((0, None)), parse_content_range("bytes 0-99/*"));
+        assert_eq!(None, parse_content_range("bytes */1000"));
+        assert_eq!(None, parse_content_range("items 0-1/2"));
+    }
+
+    #[test]
+    fn interrupted_download() {
+        let archive = b"0123456789";
+        let server = TestServer::serve(vec![
+            Response::ok(r#"{"dl": "$SERVER/dl/{crate}-{version}.crate"}"#),
+            // Connection closes before the whole archive is sent.
+            Response::ok(&archive[..4])
+                .with_header("Content-Length", "10")
+                .with_header("ETag", "\"v1\""),
+            Response::new(206, &archive[4..]).with_header("Content-Range", "bytes 4-9/10"),
+        ]);
+        let (client, registry) = server.registry(1);
+        let version = Version::parse("1.0.0").unwrap();
+
+        let mut download = download_crate(
+            Some(&client), &registry, "foo", &version, Some(&sha256_hex(archive)), true,
+            LocalArchives::default()).unwrap();
+        let mut bytes = vec![];
+        download.read_to_end(&mut bytes).unwrap();
+        assert_eq!(archive.to_vec(), bytes);
+        assert_eq!(10, download.finish().unwrap().size);
+
+        let requests = server.requests();
+        assert_eq!(Some("bytes=4-"), requests[2].header("Range"));
+        assert_eq!(Some("\"v1\""), requests[2].header("If-Range"));
+    }
+
+    #[test]
+    fn restarted_download() {
+        let archive = b"0123456789";
+        let server = TestServer::serve(vec![
+            Response::ok(r#"{"dl": "$SERVER/dl/{crate}-{version}.crate"}"#),
+            Response::ok(&archive[..4])
+                .with_header("Content-Length", "10")
+                .with_header("ETag", "\"v1\""),
+            // The range is ignored and the whole archive is sent again, with a new ETag,
+            // but the connection closes again.
+            Response::ok(&archive[..7])
+                .with_header("Content-Length", "10")
+                .with_header("ETag", "\"v2\""),
+            Response::new(206, &archive[7..]).with_header("Content-Range", "bytes 7-9/10"),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("foo-1.0.0.crate.part");
+        let (client, registry) = server.registry(1);
+        let version = Version::parse("1.0.0").unwrap();
+        let local = LocalArchives{partial: Some(&part), ..LocalArchives::default()};
+
+        let mut download = download_crate(
+            Some(&client), &registry, "foo", &version, Some(&sha256_hex(archive)), true, local).unwrap();
+        assert!(download.read_to_end(&mut vec![]).is_err());
+        drop(download);
+        // The partial download has been restarted along with its information.
+        assert_eq!(archive[..7].to_vec(), fs::read(&part).unwrap());
+        let info: PartInfo = serde_json::from_slice(&fs::read(info_path(&part)).unwrap()).unwrap();
+        assert_eq!(Some("\"v2\"".to_owned()), info.etag);
+
+        let mut download = download_crate(
+            Some(&client), &registry, "foo", &version, Some(&sha256_hex(archive)), true, local).unwrap();
+        let mut bytes = vec![];
+        download.read_to_end(&mut bytes).unwrap();
+        assert_eq!(archive.to_vec(), bytes);
+        download.finish().unwrap();
+        let requests = server.requests();
+        assert_eq!(Some("bytes=7-"), requests[3].header("Range"));
+        assert_eq!(Some("\"v2\""), requests[3].header("If-Range"));
+    }
+
+    #[test]
+    fn saved_download() {
+        let archive = b"0123456789";
+        let server = TestServer::serve(vec![
+            Response::ok(r#"{"dl": "$SERVER/dl/{crate}-{version}.crate"}"#),
+            Response::ok(&archive[..]),
+            Response::ok("tampered"),
+            Response::ok(&archive[..]),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("foo-1.0.0.crate");
+        let part = dir.path().join("foo-1.0.0.crate.part");
+        let (client, registry) = server.registry(0);
+        let version = Version::parse("1.0.0").unwrap();
+        let checksum = sha256_hex(archive);
+        let local = LocalArchives{partial: Some(&part), ..LocalArchives::default()};
+
+        // The archive is only written to its partial download, which then becomes the output.
+        let download = download_crate(
+            Some(&client), &registry, "foo", &version, Some(&checksum), true, local).unwrap();
+        download.save(&output).unwrap();
+        assert_eq!(archive.to_vec(), fs::read(&output).unwrap());
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+
+        // An archive that fails verification is never written to the output.
+        fs::remove_file(&output).unwrap();
+        let download = download_crate(
+            Some(&client), &registry, "foo", &version, Some(&checksum), true, local).unwrap();
+        match download.save(&output) {
+            Err(DownloadError::ChecksumMismatch{..}) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+
+        // Without a partial download, it's kept in a temporary file until then.
+        let download = download_crate(
+            Some(&client), &registry, "foo", &version, Some(&checksum), true,
+            LocalArchives::default()).unwrap();
+        download.save(&output).unwrap();
+        assert_eq!(archive.to_vec(), fs::read(&output).unwrap());
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn resumed_download() {
+        let archive = b"0123456789";
+        let server = TestServer::serve(vec![
+            Response::ok(r#"{"dl": "$SERVER/dl/{crate}-{version}.crate"}"#),
+            Response::new(206, &archive[4..]).with_header("Content-Range", "bytes 4-9/10"),
+            // Server that doesn't support range requests sends the whole archive.
+            Response::ok(&archive[..]),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("foo-1.0.0.crate.part");
+        let (client, registry) = server.registry(0);
+        let version = Version::parse("1.0.0").unwrap();
+        let url = format!("{}/dl/foo-1.0.0.crate", server.url());
+
+        for _ in 0..2 {
+            fs::write(&part, &archive[..4]).unwrap();
+            fs::write(info_path(&part),
+                format!(r#"{{"url": "{}", "etag": null, "length": 10}}"#, url)).unwrap();
+            let mut download = download_crate(
+                Some(&client), &registry, "foo", &version, Some(&sha256_hex(archive)), true,
+                LocalArchives{partial: Some(&part), ..LocalArchives::default()}).unwrap();
+            let mut bytes = vec![];
+            download.read_to_end(&mut bytes).unwrap();
+            assert_eq!(archive.to_vec(), bytes);
+            download.finish().unwrap();
+            // Finished downloads are cleaned up.
+            assert!(!part.exists());
+            assert!(!info_path(&part).exists());
+        }
+        assert_eq!(Some("bytes=4-"), server.requests()[2].header("Range"));
+    }
+}
@@ -0,0 +1,834 @@
+//! Module for extracting crate archives.
+//!
+//! Since crates are often downloaded precisely to inspect untrusted code,
+//! the archive is not trusted either: entries that could write outside
+//! of the crate's directory, or create anything but regular files,
+//! directories, and links within it, are rejected.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use glob::{MatchOptions, Pattern};
+use semver::Version;
+use strsim;
+use tar::{Archive, Entry, EntryType};
+use tempfile::{self, TempDir};
+
+
+/// Which entries of a crate archive to extract, and where.
+///
+/// Paths are matched and stripped relative to the crate's top-level
+/// `$NAME-$VERSION` directory, whose contents always end up directly in the destination.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// Number of leading path components to strip.
+    /// Entries that don't have more components than that are skipped.
+    pub strip_components: usize,
+    /// Glob patterns of the paths to extract. If empty, everything is extracted.
+    pub include: Vec<Pattern>,
+    /// Glob patterns of the paths to skip, even if they're included.
+    pub exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    /// Whether the filter extracts the whole crate as it is.
+    #[inline]
+    pub fn is_noop(&self) -> bool {
+        self.strip_components == 0 && self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether the path passes the include/exclude patterns.
+    fn matches(&self, path: &Path) -> bool {
+        let options = MatchOptions{require_literal_separator: true, ..MatchOptions::new()};
+        let included = self.include.is_empty()
+            || self.include.iter().any(|p| p.matches_path_with(path, options));
+        included && !self.exclude.iter().any(|p| p.matches_path_with(path, options))
+    }
+
+    /// Strip the leading components of the path,
+    /// or return `None` if there is nothing left.
+    fn strip(&self, path: &Path) -> Option<PathBuf> {
+        let stripped: PathBuf = path.components().skip(self.strip_components).collect();
+        if stripped.as_os_str().is_empty() { None } else { Some(stripped) }
+    }
+}
+
+
+/// Extract the gzipped archive of given crate, to be moved to `dest` afterwards.
+///
+/// The archive is extracted into a temporary directory next to `dest`,
+/// which is removed unless the crate is then moved into place with `Extracted::persist`.
+/// An existing `dest` is only overwritten if `force` is true.
+///
+/// Unsafe entries are reported and skipped, but the extraction then fails
+/// with `ExtractError::Unsafe` once the whole archive has been processed.
+pub fn extract_crate<R: Read>(archive: R, dest: &Path, name: &str, version: &Version,
+                              filter: &Filter, force: bool) -> Result<Extracted, ExtractError> {
+    if !force && dest.exists() {
+        return Err(ExtractError::Exists(dest.to_owned()));
+    }
+    let parent = match dest.parent() {
+        Some(p) if p != Path::new("") => p,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(parent)?;
+    let top = format!("{}-{}", name, version);
+    let temp = tempfile::Builder::new().prefix(&format!(".{}.", top)).tempdir_in(parent)?;
+    trace!("Extracting crate `{}=={}` into temporary directory {}",
+        name, version, temp.path().display());
+    let root = temp.path().join(&top);
+    fs::create_dir(&root)?;
+
+    let mut archive = Archive::new(GzDecoder::new(archive)?);
+    let (mut extracted, mut filtered, mut rejected) = (0, 0, 0);
+    // Symlinks extracted so far, which later entries must not be written through.
+    let mut symlinks = Symlinks::default();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let action = check_entry(&entry, &path, &top, filter)
+            .and_then(|action| symlinks.check(&action).map(|_| action));
+        match action {
+            Ok(Action::Unpack(target)) => {
+                debug!("Extracting {}", target.display());
+                let dst = root.join(&target);
+                if let Some(parent) = dst.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                entry.unpack(&dst)?;
+                extracted += 1;
+            }
+            Ok(Action::Symlink(target, resolved)) => {
+                debug!("Extracting {} (symlink)", target.display());
+                let dst = root.join(&target);
+                if let Some(parent) = dst.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                entry.unpack(&dst)?;
+                symlinks.insert(target, resolved);
+                extracted += 1;
+            }
+            Ok(Action::HardLink(target, link)) => {
+                let (dst, src) = (root.join(&target), root.join(&link));
+                if !src.exists() {
+                    warn!("Skipping hard link {} to {}, which hasn't been extracted",
+                        path.display(), link.display());
+                    continue;
+                }
+                debug!("Extracting {} (hard link to {})", target.display(), link.display());
+                if let Some(parent) = dst.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::hard_link(src, dst)?;
+                extracted += 1;
+            }
+            Ok(Action::Skip) => filtered += 1,
+            Err(reason) => {
+                warn!("Rejected archive entry {}: {}", path.display(), reason);
+                rejected += 1;
+            }
+        }
+    }
+    if rejected > 0 {
+        return Err(ExtractError::Unsafe(rejected));
+    }
+    if !filter.is_noop() {
+        debug!("Extracted {} archive entries, {} filtered out", extracted, filtered);
+    }
+    Ok(Extracted{temp, top})
+}
+
+
+/// Copy a single file from the gzipped archive of given crate to `output`,
+/// without extracting it. Returns the number of bytes copied.
+///
+/// The `path` is relative to the crate's top-level directory (e.g. "src/lib.rs").
+/// If there is no such file, the error lists those with similar paths.
+/// Symlinks and hard links aren't followed, since their targets may have already
+/// been passed in the archive; asking for one is an error of its own.
+pub fn read_file<R: Read, W: Write>(archive: R, name: &str, version: &Version,
+                                    path: &Path, output: &mut W) -> Result<u64, ExtractError> {
+    let top = format!("{}-{}", name, version);
+    let wanted: PathBuf = path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect();
+    let mut archive = Archive::new(GzDecoder::new(archive)?);
+    let mut files = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let link = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => None,
+            EntryType::Symlink => Some(FileKind::Symlink),
+            EntryType::Link => Some(FileKind::HardLink),
+            _ => continue,
+        };
+        // Unsafe entries aren't written anywhere here, but they can't be asked for either.
+        let path = match check_path(&entry.path()?, &top) {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+        if path == wanted {
+            if let Some(kind) = link {
+                return Err(ExtractError::Link(wanted, kind));
+            }
+            trace!("Found {} in the archive of crate `{}=={}` ({} bytes)",
+                path.display(), name, version, entry.header().size()?);
+            return Ok(io::copy(&mut entry, output)?);
+        }
+        if link.is_none() {
+            files.push(path);
+        }
+    }
+    Err(ExtractError::NotFound(wanted.clone(), similar_paths(&wanted, &files)))
+}
+
+/// Single entry of a crate archive, as listed by `list_entries`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FileEntry {
+    /// Path of the entry, exactly as it's stored in the archive.
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: FileKind,
+    /// Size of the entry's content in bytes.
+    pub size: u64,
+    /// Unix permission bits of the entry.
+    pub mode: u32,
+    /// Target of a symlink or hard link.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+/// Type of an archive entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    HardLink,
+    /// Anything else, like a device file or FIFO.
+    Other,
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FileKind::File => write!(fmt, "file"),
+            FileKind::Dir => write!(fmt, "dir"),
+            FileKind::Symlink => write!(fmt, "symlink"),
+            FileKind::HardLink => write!(fmt, "hardlink"),
+            FileKind::Other => write!(fmt, "other"),
+        }
+    }
+}
+
+/// List the entries of the gzipped archive of a crate, in the order they're stored.
+///
+/// Nothing is extracted, so the entries are listed as they are,
+/// including any that `extract_crate` would reject.
+pub fn list_entries<R: Read>(archive: R) -> Result<Vec<FileEntry>, ExtractError> {
+    let mut archive = Archive::new(GzDecoder::new(archive)?);
+    let mut entries = vec![];
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => FileKind::File,
+            EntryType::Directory => FileKind::Dir,
+            EntryType::Symlink => FileKind::Symlink,
+            EntryType::Link => FileKind::HardLink,
+            EntryType::XGlobalHeader | EntryType::XHeader => continue,
+            _ => FileKind::Other,
+        };
+        entries.push(FileEntry{
+            path: entry.path()?.to_string_lossy().into_owned(),
+            kind,
+            size: header.size()?,
+            mode: header.mode()?,
+            link: entry.link_name()?.map(|l| l.to_string_lossy().into_owned()),
+        });
+    }
+    Ok(entries)
+}
+
+/// Maximum number of similar paths to suggest when a file isn't found.
+const MAX_SIMILAR_PATHS: usize = 5;
+
+/// Pick the paths similar to the given one, most similar first.
+fn similar_paths(wanted: &Path, paths: &[PathBuf]) -> Vec<String> {
+    let wanted_name = wanted.file_name();
+    let wanted = wanted.to_string_lossy();
+    let mut similar: Vec<_> = paths.iter()
+        .filter_map(|p| {
+            let path = p.to_string_lossy().into_owned();
+            // Same file in a different directory is a likely candidate, too.
+            let score = if p.file_name() == wanted_name { 1.0 }
+                else { strsim::jaro_winkler(&wanted, &path) };
+            if score > 0.8 { Some((score, path)) } else { None }
+        })
+        .collect();
+    similar.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap().then_with(|| a.1.cmp(&b.1)));
+    similar.into_iter().take(MAX_SIMILAR_PATHS).map(|(_, p)| p).collect()
+}
+
+
+/// Crate extracted into a temporary directory.
+/// Unless it's persisted, the directory is removed when this is dropped.
+#[derive(Debug)]
+pub struct Extracted {
+    temp: TempDir,
+    /// Name of the crate's top-level directory inside `temp`.
+    top: String,
+}
+
+impl Extracted {
+    /// Move the extracted crate to its destination.
+    ///
+    /// Since the temporary directory is on the same filesystem, this is a rename.
+    /// If `force` is true, an existing `dest` is replaced.
+    ///
+    /// Replacing takes two renames: the existing `dest` is first moved into
+    /// the temporary directory (to be removed along with it), and then the crate
+    /// is moved into place. In between, `dest` briefly doesn't exist. If the second
+    /// rename fails, the original `dest` is moved back before the error is returned.
+    pub fn persist(self, dest: &Path, force: bool) -> Result<(), ExtractError> {
+        let crate_dir = self.temp.path().join(&self.top);
+        if !dest.exists() {
+            fs::rename(&crate_dir, dest)?;
+            return Ok(());
+        }
+        if !force {
+            return Err(ExtractError::Exists(dest.to_owned()));
+        }
+        debug!("Replacing existing {}", dest.display());
+        let replaced = self.temp.path().join("replaced");
+        fs::rename(dest, &replaced)?;
+        if let Err(e) = fs::rename(&crate_dir, dest) {
+            if let Err(restore_error) = fs::rename(&replaced, dest) {
+                // Keep the temporary directory, so that the original isn't lost.
+                let temp = self.temp.into_path();
+                error!("Failed to restore {} (it's been left in {}): {}",
+                    dest.display(), temp.join("replaced").display(), restore_error);
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+
+/// What to do with an archive entry.
+#[derive(Debug, PartialEq)]
+enum Action {
+    /// Unpack the entry to given path (relative to the crate's directory).
+    Unpack(PathBuf),
+    /// Unpack the symlink to the first path. The second one lists the paths
+    /// that resolving it goes through (see `resolve_symlink`).
+    Symlink(PathBuf, Vec<PathBuf>),
+    /// Create a hard link at the first path to the second one.
+    /// This isn't left to the `tar` crate, which would resolve the latter
+    /// against the current directory.
+    HardLink(PathBuf, PathBuf),
+    Skip,
+}
+
+/// Check whether an archive entry is safe to extract,
+/// and whether it passes the filter.
+fn check_entry<R: Read>(entry: &Entry<R>, path: &Path, top: &str,
+                        filter: &Filter) -> Result<Action, String> {
+    let entry_type = entry.header().entry_type();
+    let path = check_path(path, top)?;
+    let is_link = entry_type.is_symlink() || entry_type.is_hard_link();
+    if is_link && path.as_os_str().is_empty() {
+        return Err("top-level directory is a link".into());
+    }
+    match entry_type {
+        EntryType::Regular | EntryType::Continuous | EntryType::Directory
+            | EntryType::Symlink | EntryType::Link => {}
+        EntryType::Char | EntryType::Block => return Err("device file".into()),
+        EntryType::Fifo => return Err("FIFO".into()),
+        EntryType::XGlobalHeader => return Ok(Action::Skip),
+        t => return Err(format!("unsupported entry type {:?}", t)),
+    }
+
+    if path.as_os_str().is_empty() {
+        return Ok(Action::Skip);  // top-level directory, which always exists
+    }
+    let target = match filter.strip(&path) {
+        Some(ref t) if filter.matches(&path) => t.clone(),
+        _ => {
+            debug!("Skipping {} (filtered out)", path.display());
+            return Ok(Action::Skip);
+        }
+    };
+    match entry_type {
+        EntryType::Symlink => {
+            // Symlink targets are relative to the directory containing the link,
+            // and must stay within the extracted part of the crate.
+            let link = link_name(entry)?;
+            check_symlink(&link, target.components().count() - 1)?;
+            let resolved = resolve_symlink(&target, &link);
+            Ok(Action::Symlink(target, resolved))
+        }
+        EntryType::Link => {
+            // Hard link targets are relative to the archive root.
+            let link = link_name(entry)?;
+            let link = check_path(&link, top).map_err(|e| format!("hard link target {}", e))?;
+            match filter.strip(&link) {
+                Some(link) => Ok(Action::HardLink(target, link)),
+                None => {
+                    warn!("Skipping hard link {} to the stripped path {}",
+                        path.display(), link.display());
+                    Ok(Action::Skip)
+                }
+            }
+        }
+        _ => Ok(Action::Unpack(target)),
+    }
+}
+
+/// Symlinks extracted so far, and the paths their targets go through.
+#[derive(Debug, Default)]
+struct Symlinks {
+    links: HashSet<PathBuf>,
+    resolved: HashSet<PathBuf>,
+}
+
+impl Symlinks {
+    fn insert(&mut self, path: PathBuf, resolved: Vec<PathBuf>) {
+        self.links.insert(path);
+        self.resolved.extend(resolved);
+    }
+
+    /// Check that the paths an action writes to (or hard links from) don't go through
+    /// any of the symlinks extracted before, nor are those symlinks themselves.
+    /// A new symlink's target must not go through them either, and the symlink
+    /// can't be created where the target of an earlier one goes through.
+    ///
+    /// Otherwise, a chain of symlinks that each stay within the crate on their own
+    /// (like `a/b -> ..`, `a/b/c -> ..`, and so on, or `x/y -> ..` and `z -> x/y/..`)
+    /// could be used to write outside of it.
+    fn check(&self, action: &Action) -> Result<(), String> {
+        let paths: Vec<&Path> = match *action {
+            Action::Unpack(ref target) | Action::Symlink(ref target, _) => vec![target],
+            Action::HardLink(ref target, ref link) => vec![target, link],
+            Action::Skip => vec![],
+        };
+        for path in paths {
+            if let Some(symlink) = path.ancestors().find(|a| self.links.contains(*a)) {
+                return Err(format!("path goes through the symlink {}", symlink.display()));
+            }
+        }
+        if let Action::Symlink(ref target, ref resolved) = *action {
+            if let Some(symlink) = resolved.iter().find(|p| self.links.contains(*p)) {
+                return Err(format!("symlink target goes through the symlink {}",
+                    symlink.display()));
+            }
+            if self.resolved.contains(target) {
+                return Err("an earlier symlink's target goes through this path".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn link_name<R: Read>(entry: &Entry<R>) -> Result<PathBuf, String> {
+    match entry.link_name() {
+        Ok(Some(target)) => Ok(target.into_owned()),
+        Ok(None) => Err("link without a target".into()),
+        Err(e) => Err(format!("invalid link target: {}", e)),
+    }
+}
+
+/// Check that the path is relative, has no `..` components,
+/// and lies within the top-level directory of given name.
+/// Returns the path relative to that directory.
+fn check_path(path: &Path, top: &str) -> Result<PathBuf, String> {
+    let mut parts = vec![];
+    for component in path.components() {
+        match component {
+            Component::Prefix(..) | Component::RootDir =>
+                return Err("is an absolute path".into()),
+            Component::ParentDir => return Err("contains a `..` component".into()),
+            Component::CurDir => {}
+            Component::Normal(part) => parts.push(part),
+        }
+    }
+    if parts.first() != Some(&top.as_ref()) {
+        return Err(format!("is outside of the {}/ directory", top));
+    }
+    Ok(parts[1..].iter().collect())
+}
+
+/// Check that a symlink target, relative to a directory at given depth
+/// below the root of the extracted files, stays within the latter.
+fn check_symlink(target: &Path, depth: usize) -> Result<(), String> {
+    let mut depth = depth;
+    for component in target.components() {
+        match component {
+            Component::Prefix(..) | Component::RootDir =>
+                return Err(format!("symlink to an absolute path {}", target.display())),
+            Component::ParentDir => {
+                if depth == 0 {
+                    return Err(format!("symlink pointing outside of the crate: {}",
+                        target.display()));
+                }
+                depth -= 1;
+            }
+            Component::CurDir => {}
+            Component::Normal(_) => depth += 1,
+        }
+    }
+    Ok(())
+}
+
+/// Paths (relative to the root of the extracted files) that resolving a symlink
+/// at `path` to `target` goes through, in order. `target` must already be checked
+/// with `check_symlink`. Any of these paths that's a symlink itself would make
+/// the lexical check meaningless, since `..` then leads somewhere else on disk.
+fn resolve_symlink(path: &Path, target: &Path) -> Vec<PathBuf> {
+    let mut current = path.parent().map(Path::to_owned).unwrap_or_default();
+    let mut resolved = vec![];
+    for component in target.components() {
+        match component {
+            Component::ParentDir => { current.pop(); }
+            Component::Normal(part) => {
+                current.push(part);
+                resolved.push(current.clone());
+            }
+            _ => {}
+        }
+    }
+    resolved
+}
+
+
+/// Error that can occur while extracting a crate archive.
+#[derive(Debug)]
+pub enum ExtractError {
+    /// I/O error while reading the archive or writing its files.
+    Io(io::Error),
+    /// Archive contains given number of unsafe entries.
+    Unsafe(usize),
+    /// Destination of the extracted crate already exists.
+    Exists(PathBuf),
+    /// File isn't in the archive. Includes similar paths that are.
+    NotFound(PathBuf, Vec<String>),
+    /// File asked for is a link in the archive, which isn't followed.
+    Link(PathBuf, FileKind),
+}
+impl From<io::Error> for ExtractError {
+    fn from(input: io::Error) -> Self {
+        ExtractError::Io(input)
+    }
+}
+impl Error for ExtractError {
+    fn description(&self) -> &str { "failed to extract crate archive" }
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            ExtractError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for ExtractError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExtractError::Io(ref e) => write!(fmt, "I/O error: {}", e),
+            ExtractError::Exists(ref p) =>
+                write!(fmt, "{} already exists (pass --force to overwrite it)", p.display()),
+            ExtractError::Unsafe(n) =>
+                write!(fmt, "archive contains {} unsafe entr{}", n, if n == 1 { "y" } else { "ies" }),
+            ExtractError::NotFound(ref p, ref similar) => {
+                write!(fmt, "file {} not found in the crate", p.display())?;
+                if !similar.is_empty() {
+                    write!(fmt, " (did you mean: {}?)", similar.join(", "))?;
+                }
+                Ok(())
+            }
+            ExtractError::Link(ref p, kind) =>
+                write!(fmt, "{} is a {} in the crate, not a regular file", p.display(), kind),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use semver::Version;
+    use glob::Pattern;
+    use tar::{Builder, EntryType, Header};
+    use tempfile;
+
+    use super::{check_path, check_symlink, extract_crate, list_entries, read_file,
+                ExtractError, FileKind, Filter};
+
+    /// Build a gzipped archive from (path, entry type, link target or content) triples.
+    /// Paths are written into the headers verbatim, bypassing any validation.
+    fn archive(entries: &[(&str, EntryType, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(vec![], Compression::Default));
+        for &(path, entry_type, data) in entries {
+            let mut header = Header::new_old();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(entry_type);
+            header.set_mode(0o644);
+            let data = match entry_type {
+                EntryType::Symlink | EntryType::Link => {
+                    header.as_old_mut().linkname[..data.len()].copy_from_slice(data.as_bytes());
+                    ""
+                }
+                _ => data,
+            };
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(Ok(PathBuf::new()), check_path(Path::new("foo-1.0.0/"), "foo-1.0.0"));
+        assert_eq!(Ok(PathBuf::from("src/lib.rs")),
+            check_path(Path::new("./foo-1.0.0/src/lib.rs"), "foo-1.0.0"));
+        assert!(check_path(Path::new("/foo-1.0.0/src"), "foo-1.0.0").is_err());
+        assert!(check_path(Path::new("foo-1.0.0/../evil"), "foo-1.0.0").is_err());
+        assert!(check_path(Path::new("bar-1.0.0/src"), "foo-1.0.0").is_err());
+        assert!(check_path(Path::new("."), "foo-1.0.0").is_err());
+    }
+
+    #[test]
+    fn symlinks() {
+        assert!(check_symlink(Path::new("lib.rs"), 0).is_ok());
+        assert!(check_symlink(Path::new("../README.md"), 1).is_ok());
+        assert!(check_symlink(Path::new("../README.md"), 0).is_err());
+        assert!(check_symlink(Path::new("a/../../b"), 0).is_err());
+        assert!(check_symlink(Path::new("/etc/passwd"), 3).is_err());
+    }
+
+    #[test]
+    fn safe_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = archive(&[
+            ("foo-1.0.0/", EntryType::Directory, ""),
+            ("foo-1.0.0/src/lib.rs", EntryType::Regular, "pub fn foo() {}"),
+            ("foo-1.0.0/src/link.rs", EntryType::Symlink, "lib.rs"),
+            ("foo-1.0.0/hard.rs", EntryType::Link, "foo-1.0.0/src/lib.rs"),
+        ]);
+        let version = Version::parse("1.0.0").unwrap();
+        let crate_dir = dir.path().join("out/foo");
+        extract_crate(&archive[..], &crate_dir, "foo", &version, &Filter::default(), false).unwrap()
+            .persist(&crate_dir, false).unwrap();
+        assert_eq!("pub fn foo() {}", fs::read_to_string(crate_dir.join("src/link.rs")).unwrap());
+        assert_eq!("pub fn foo() {}", fs::read_to_string(crate_dir.join("hard.rs")).unwrap());
+        // Only the extracted crate is left behind.
+        assert_eq!(1, fs::read_dir(dir.path().join("out")).unwrap().count());
+    }
+
+    #[test]
+    fn existing_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let crate_dir = dir.path().join("foo-1.0.0");
+        fs::create_dir(&crate_dir).unwrap();
+        fs::write(crate_dir.join("old.rs"), "").unwrap();
+        let archive = archive(&[("foo-1.0.0/new.rs", EntryType::Regular, "")]);
+        let version = Version::parse("1.0.0").unwrap();
+
+        match extract_crate(&archive[..], &crate_dir, "foo", &version, &Filter::default(), false) {
+            Err(ExtractError::Exists(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let extracted = extract_crate(&archive[..], &crate_dir, "foo", &version, &Filter::default(), true).unwrap();
+        match extracted.persist(&crate_dir, false) {
+            Err(ExtractError::Exists(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(crate_dir.join("old.rs").exists());
+
+        extract_crate(&archive[..], &crate_dir, "foo", &version, &Filter::default(), true).unwrap()
+            .persist(&crate_dir, true).unwrap();
+        assert!(!crate_dir.join("old.rs").exists());
+        assert!(crate_dir.join("new.rs").exists());
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn failed_replacement() {
+        let dir = tempfile::tempdir().unwrap();
+        let crate_dir = dir.path().join("foo-1.0.0");
+        fs::create_dir(&crate_dir).unwrap();
+        fs::write(crate_dir.join("old.rs"), "").unwrap();
+        let archive = archive(&[("foo-1.0.0/new.rs", EntryType::Regular, "")]);
+        let version = Version::parse("1.0.0").unwrap();
+
+        // Make moving the extracted crate into place fail
+        // after the existing directory has been moved out of the way.
+        let extracted = extract_crate(&archive[..], &crate_dir, "foo", &version, &Filter::default(), true).unwrap();
+        fs::remove_dir_all(extracted.temp.path().join(&extracted.top)).unwrap();
+        match extracted.persist(&crate_dir, true) {
+            Err(ExtractError::Io(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(crate_dir.join("old.rs").exists());
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn unsafe_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = archive(&[
+            ("foo-1.0.0/src/lib.rs", EntryType::Regular, "pub fn foo() {}"),
+            ("/tmp/absolute", EntryType::Regular, "evil"),
+            ("foo-1.0.0/../../traversal", EntryType::Regular, "evil"),
+            ("elsewhere", EntryType::Regular, "evil"),
+            ("foo-1.0.0/passwd", EntryType::Symlink, "/etc/passwd"),
+            ("foo-1.0.0/up", EntryType::Symlink, "../.."),
+            ("foo-1.0.0/hard", EntryType::Link, "/etc/passwd"),
+            ("foo-1.0.0/fifo", EntryType::Fifo, ""),
+            ("foo-1.0.0", EntryType::Symlink, "foo-1.0.0/src"),
+        ]);
+        let version = Version::parse("1.0.0").unwrap();
+        match extract_crate(&archive[..], &dir.path().join("out/foo"), "foo", &version, &Filter::default(), false) {
+            Err(ExtractError::Unsafe(8)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        // Nothing is left behind, including the temporary directory.
+        assert_eq!(0, fs::read_dir(dir.path().join("out")).unwrap().count());
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn symlink_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        // Each symlink stays within the crate from its own location,
+        // but writing through all of them would end up outside of it.
+        let chain = archive(&[
+            ("foo-1.0.0/a/b", EntryType::Symlink, ".."),
+            ("foo-1.0.0/a/b/c", EntryType::Symlink, ".."),
+            ("foo-1.0.0/a/b/c/d", EntryType::Symlink, ".."),
+            ("foo-1.0.0/a/b/c/d/pwned", EntryType::Regular, "evil"),
+            ("foo-1.0.0/hard", EntryType::Link, "foo-1.0.0/a/b/c/d/pwned"),
+            ("foo-1.0.0/a/b", EntryType::Regular, "evil"),
+        ]);
+        let version = Version::parse("1.0.0").unwrap();
+        let dest = dir.path().join("out/foo");
+        match extract_crate(&chain[..], &dest, "foo", &version, &Filter::default(), false) {
+            Err(ExtractError::Unsafe(5)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(!dir.path().join("pwned").exists());
+        assert!(!dir.path().join("out/pwned").exists());
+        assert_eq!(0, fs::read_dir(dir.path().join("out")).unwrap().count());
+
+        // Symlink targets can't go through earlier symlinks either, in any order:
+        // x/y/.. is the crate's root lexically, but its parent on disk.
+        for entries in &[
+            [("foo-1.0.0/x/y", EntryType::Symlink, ".."), ("foo-1.0.0/z", EntryType::Symlink, "x/y/..")],
+            [("foo-1.0.0/z", EntryType::Symlink, "x/y/.."), ("foo-1.0.0/x/y", EntryType::Symlink, "..")],
+        ] {
+            let chain = archive(entries);
+            match extract_crate(&chain[..], &dest, "foo", &version, &Filter::default(), false) {
+                Err(ExtractError::Unsafe(1)) => {}
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn filtered_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut entries = vec![
+            ("foo-1.0.0/", EntryType::Directory, ""),
+            ("foo-1.0.0/Cargo.toml", EntryType::Regular, "[package]"),
+            ("foo-1.0.0/src/lib.rs", EntryType::Regular, "pub fn foo() {}"),
+            ("foo-1.0.0/src/bin/main.rs", EntryType::Regular, "fn main() {}"),
+            ("foo-1.0.0/src/tests.rs", EntryType::Regular, "#[test]"),
+            ("foo-1.0.0/src/link.rs", EntryType::Symlink, "lib.rs"),
+            ("foo-1.0.0/src/hard.rs", EntryType::Link, "foo-1.0.0/src/lib.rs"),
+        ];
+        let version = Version::parse("1.0.0").unwrap();
+        let crate_dir = dir.path().join("foo");
+        let filter = Filter{
+            strip_components: 1,
+            include: vec![Pattern::new("src/*.rs").unwrap()],
+            exclude: vec![Pattern::new("src/tests.rs").unwrap()],
+        };
+        extract_crate(&archive(&entries)[..], &crate_dir, "foo", &version, &filter, false).unwrap()
+            .persist(&crate_dir, false).unwrap();
+        let mut files: Vec<_> = fs::read_dir(&crate_dir).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(vec!["hard.rs", "lib.rs", "link.rs"], files);
+        assert_eq!("pub fn foo() {}", fs::read_to_string(crate_dir.join("link.rs")).unwrap());
+
+        // Unsafe entries are rejected even if they'd be filtered out.
+        entries.push(("foo-1.0.0/../evil", EntryType::Regular, "evil"));
+        match extract_crate(&archive(&entries)[..], &crate_dir, "foo", &version, &filter, true) {
+            Err(ExtractError::Unsafe(1)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn single_file() {
+        let archive = archive(&[
+            ("foo-1.0.0/", EntryType::Directory, ""),
+            ("foo-1.0.0/build.rs", EntryType::Regular, "fn main() {}"),
+            ("foo-1.0.0/src/lib.rs", EntryType::Regular, "pub fn foo() {}"),
+            ("foo-1.0.0/src/bin/build.rs", EntryType::Regular, ""),
+            ("foo-1.0.0/src/link.rs", EntryType::Symlink, "lib.rs"),
+        ]);
+        let version = Version::parse("1.0.0").unwrap();
+        let read = |path: &str| {
+            let mut content = vec![];
+            read_file(&archive[..], "foo", &version, Path::new(path), &mut content)
+                .map(|_| content)
+        };
+        assert_eq!(b"pub fn foo() {}".to_vec(), read("./src/lib.rs").unwrap());
+        match read("src/build.rs") {
+            Err(ExtractError::NotFound(_, ref similar)) =>
+                assert_eq!(&["build.rs", "src/bin/build.rs", "src/lib.rs"], &similar[..]),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match read("src") {
+            Err(ExtractError::NotFound(..)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match read("src/link.rs") {
+            Err(ExtractError::Link(_, FileKind::Symlink)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn entries() {
+        let archive = archive(&[
+            ("foo-1.0.0/", EntryType::Directory, ""),
+            ("foo-1.0.0/src/lib.rs", EntryType::Regular, "pub fn foo() {}"),
+            ("foo-1.0.0/src/link.rs", EntryType::Symlink, "lib.rs"),
+            ("/etc/passwd", EntryType::Regular, ""),
+        ]);
+        let entries = list_entries(&archive[..]).unwrap();
+        let listed: Vec<_> = entries.iter()
+            .map(|e| (&e.path[..], e.kind, e.size, e.link.as_ref().map(|l| &l[..])))
+            .collect();
+        assert_eq!(vec![
+            ("foo-1.0.0/", FileKind::Dir, 0, None),
+            ("foo-1.0.0/src/lib.rs", FileKind::File, 15, None),
+            ("foo-1.0.0/src/link.rs", FileKind::Symlink, 0, Some("lib.rs")),
+            ("/etc/passwd", FileKind::File, 0, None),
+        ], listed);
+        assert_eq!(0o644, entries[1].mode);
+    }
+}
@@ -0,0 +1,541 @@
+//! Module for making HTTP requests to registries,
+//! retrying the ones that fail for transient reasons.
+//!
+//! The client is configured like Cargo's own, from the `[http]` section of its config
+//! (https://doc.rust-lang.org/cargo/reference/config.html#http).
+
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use reqwest::{self, Certificate, Proxy, RedirectPolicy, Response, StatusCode, Url};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, LOCATION, RETRY_AFTER, USER_AGENT};
+use time;
+
+use config::{Config, ConfigError};
+
+
+/// Delay before the first retry, doubled with every subsequent one.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Maximum delay between retries, including one requested with `Retry-After`.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Maximum number of redirects to follow for a single request.
+const MAX_REDIRECTS: usize = 10;
+
+
+/// HTTP client that retries requests failing with network errors
+/// or server responses asking to try again later.
+#[derive(Clone, Debug)]
+pub struct Client {
+    inner: reqwest::Client,
+    /// Maximum number of retries of a single request.
+    retries: u32,
+    initial_backoff: Duration,
+}
+
+impl Client {
+    pub fn new(config: &HttpConfig, retries: u32) -> Result<Self, HttpError> {
+        // Redirects are followed by us, so that tokens are only sent where they belong.
+        let mut builder = reqwest::Client::builder().redirect(RedirectPolicy::none());
+        if config.http_proxy.is_some() || config.https_proxy.is_some() {
+            let parse = |proxy: &Option<String>| match *proxy {
+                Some(ref proxy) => {
+                    let url = Url::parse(proxy).map_err(|_| HttpError::Proxy(proxy.clone()))?;
+                    debug!("Using HTTP proxy {}", url);
+                    Ok(Some(url))
+                }
+                None => Ok(None),
+            };
+            let (http_proxy, https_proxy) = (parse(&config.http_proxy)?, parse(&config.https_proxy)?);
+            let no_proxy = config.no_proxy.clone();
+            builder = builder.proxy(Proxy::custom(move |url| {
+                if url.host_str().is_some_and(|host| bypasses_proxy(&no_proxy, host)) {
+                    return None;
+                }
+                match url.scheme() {
+                    "http" => http_proxy.clone(),
+                    "https" => https_proxy.clone(),
+                    _ => None,
+                }
+            }));
+        }
+        if let Some(ref path) = config.cainfo {
+            for cert in read_certificates(path)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if config.check_revoke == Some(true) {
+            // Cargo itself only supports this on Windows, and ignores it elsewhere.
+            warn!("Checking certificate revocation (http.check-revoke) isn't supported, ignoring it");
+        }
+        if let Some(timeout) = config.timeout {
+            // The blocking client applies it to connecting and receiving the response headers,
+            // and then to each read of the body, so a slow download isn't cut off
+            // as long as it doesn't stall (which is what Cargo's low-speed limit is for).
+            builder = builder.timeout(timeout);
+        }
+        if let Some(ref user_agent) = config.user_agent {
+            let value = HeaderValue::from_str(user_agent)
+                .map_err(|_| HttpError::UserAgent(user_agent.clone()))?;
+            let mut headers = HeaderMap::new();
+            headers.insert(USER_AGENT, value);
+            builder = builder.default_headers(headers);
+        }
+        let inner = builder.build().map_err(HttpError::Client)?;
+        Ok(Client{inner, retries, initial_backoff: INITIAL_BACKOFF})
+    }
+
+    /// Same client, but with given delay before the first retry (which doubles after that).
+    #[cfg(test)]
+    pub fn with_initial_backoff(self, initial_backoff: Duration) -> Self {
+        Client{initial_backoff, ..self}
+    }
+
+    /// Maximum number of retries of a single request.
+    #[inline]
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Make a GET request to given URL,
+    /// authorized with given token if it's meant for the URL.
+    ///
+    /// If all the retries fail, the last error or response is returned.
+    #[inline]
+    pub fn get(&self, url: &str, token: Option<&Token>) -> reqwest::Result<Response> {
+        self.get_with(url, HeaderMap::new(), token)
+    }
+
+    /// Make a GET request to given URL with additional headers, like `get`.
+    ///
+    /// Redirects are followed, but the token is only sent to the host it's meant for.
+    pub fn get_with(&self, url: &str, headers: HeaderMap,
+                    token: Option<&Token>) -> reqwest::Result<Response> {
+        let mut url = url.to_owned();
+        let mut redirects = 0;
+        loop {
+            let mut request_headers = headers.clone();
+            if let Some(token) = token.filter(|t| t.is_for(&url)) {
+                request_headers.insert(AUTHORIZATION, token.header.clone());
+            }
+            let response = self.send(&url, request_headers)?;
+            if !response.status().is_redirection() || redirects >= MAX_REDIRECTS {
+                return Ok(response);
+            }
+            let location = response.headers().get(LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .and_then(|l| response.url().join(l).ok());
+            match location {
+                Some(location) => {
+                    trace!("Following redirect from {} to {}", url, location);
+                    url = location.into_string();
+                    redirects += 1;
+                }
+                None => return Ok(response),
+            }
+        }
+    }
+
+    /// Send a single GET request, retrying it if it fails for transient reasons.
+    fn send(&self, url: &str, headers: HeaderMap) -> reqwest::Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let result = self.inner.get(url).headers(headers.clone()).send();
+            let (reason, retry_after) = match result {
+                Ok(ref response) if is_transient_status(response.status()) =>
+                    (format!("HTTP status {}", response.status()), retry_after(response)),
+                Err(ref e) if is_transient_error(e) => {
+                    // The error itself would repeat the URL.
+                    let cause = e.get_ref().map(|c| c.to_string());
+                    (cause.unwrap_or_else(|| e.to_string()), None)
+                }
+                _ => return result,
+            };
+            if attempt >= self.retries {
+                return result;
+            }
+            let delay = retry_after.unwrap_or_else(|| self.backoff(attempt)).min(MAX_BACKOFF);
+            attempt += 1;
+            warn!("Request to {} failed ({}), retrying in {:.1}s ({}/{})",
+                url, reason, delay.as_secs_f64(), attempt, self.retries);
+            thread::sleep(delay);
+        }
+    }
+
+    /// Delay before the retry after given (zero-based) attempt:
+    /// exponential backoff with a random jitter of up to a half of it.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.initial_backoff.saturating_mul(2u32.saturating_pow(attempt));
+        delay.mul_f64(1.0 - 0.5 * jitter())
+    }
+}
+
+
+/// Token that authorizes requests to a registry.
+///
+/// It's only ever sent to the registry's own host (with the same scheme and port),
+/// and it's never included in logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Token {
+    header: HeaderValue,
+    /// Scheme, host, and port that the token can be sent to.
+    origin: (String, Option<String>, Option<u16>),
+}
+
+impl Token {
+    /// Create the token for the registry with given index URL.
+    /// Returns `None` if the token isn't a valid header value.
+    pub fn new(token: &str, index: &str) -> Option<Self> {
+        let mut header = HeaderValue::from_str(token.trim()).ok()?;
+        header.set_sensitive(true);
+        let index = index.trim_start_matches("sparse+").trim_start_matches("registry+");
+        let origin = Url::parse(index).ok().map(|u| origin(&u))?;
+        Some(Token{header, origin})
+    }
+
+    /// Whether the token should be sent along with a request to given URL.
+    fn is_for(&self, url: &str) -> bool {
+        Url::parse(url).is_ok_and(|u| origin(&u) == self.origin)
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Token(<redacted>)")
+    }
+}
+
+fn origin(url: &Url) -> (String, Option<String>, Option<u16>) {
+    (url.scheme().to_owned(), url.host_str().map(|h| h.to_lowercase()), url.port_or_known_default())
+}
+
+
+/// Settings of the HTTP client, like Cargo's `[http]` config.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HttpConfig {
+    /// URL of the proxy to use for requests to `http://` URLs.
+    pub http_proxy: Option<String>,
+    /// URL of the proxy to use for requests to `https://` URLs.
+    pub https_proxy: Option<String>,
+    /// Hosts to connect to directly even if there is a proxy.
+    pub no_proxy: Vec<String>,
+    /// File with additional certificate authorities to trust, in PEM format.
+    pub cainfo: Option<PathBuf>,
+    /// Whether to check for revocation of TLS certificates.
+    pub check_revoke: Option<bool>,
+    /// How long to wait for a connection, the response, or any part of its body.
+    pub timeout: Option<Duration>,
+    pub user_agent: Option<String>,
+}
+
+impl HttpConfig {
+    /// Read the settings from Cargo config (or the `CARGO_HTTP_*` environment variables
+    /// that override it). Without `http.proxy`, the proxies are taken from the usual
+    /// environment variables like `HTTPS_PROXY`, and hosts in `NO_PROXY` bypass them.
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let proxy = config.get_str(&["http", "proxy"]).filter(|p| !p.is_empty());
+        let env_var = |name: &str| env::var(name).ok();
+        let (http_proxy, https_proxy) = match proxy {
+            Some(proxy) => (Some(normalize_proxy(&proxy)), Some(normalize_proxy(&proxy))),
+            None => (
+                env_proxy("http", env_var).map(|p| normalize_proxy(&p)),
+                env_proxy("https", env_var).map(|p| normalize_proxy(&p)),
+            ),
+        };
+        let no_proxy = ["NO_PROXY", "no_proxy"].iter()
+            .filter_map(|var| env::var(var).ok())
+            .next()
+            .map(|hosts| parse_no_proxy(&hosts))
+            .unwrap_or_default();
+        let timeout = match config.get_int(&["http", "timeout"])? {
+            Some(secs) if secs >= 0 => Some(Duration::from_secs(secs as u64)),
+            Some(secs) => return Err(ConfigError::Value("http.timeout".into(), secs.to_string())),
+            None => None,
+        };
+        Ok(HttpConfig{
+            http_proxy,
+            https_proxy,
+            no_proxy,
+            cainfo: config.get_path(&["http", "cainfo"]),
+            check_revoke: config.get_bool(&["http", "check-revoke"])?,
+            timeout,
+            user_agent: config.get_str(&["http", "user-agent"]),
+        })
+    }
+}
+
+/// Proxy for URLs with given scheme from the environment variables, as in curl:
+/// `https_proxy` (or `HTTPS_PROXY`) for HTTPS, `http_proxy` for HTTP
+/// (but not `HTTP_PROXY`, which can be set by CGI requests), and `all_proxy` for both.
+fn env_proxy<F: Fn(&str) -> Option<String>>(scheme: &str, env_var: F) -> Option<String> {
+    let vars: &[&str] = match scheme {
+        "https" => &["https_proxy", "HTTPS_PROXY", "all_proxy", "ALL_PROXY"],
+        _ => &["http_proxy", "all_proxy", "ALL_PROXY"],
+    };
+    vars.iter().filter_map(|var| env_var(var)).find(|p| !p.is_empty())
+}
+
+/// Add the default scheme to a proxy given just as `host:port` (which Cargo allows).
+pub fn normalize_proxy(proxy: &str) -> String {
+    if proxy.contains("://") { proxy.to_owned() } else { format!("http://{}", proxy) }
+}
+
+/// Parse a comma-separated list of hosts that shouldn't be proxied, as in `NO_PROXY`.
+fn parse_no_proxy(hosts: &str) -> Vec<String> {
+    hosts.split(',')
+        .map(|h| h.trim().trim_start_matches('.').to_lowercase())
+        .filter(|h| !h.is_empty())
+        .collect()
+}
+
+/// Whether given host should be connected to directly, rather than through the proxy.
+fn bypasses_proxy(no_proxy: &[String], host: &str) -> bool {
+    let host = host.to_lowercase();
+    no_proxy.iter().any(|entry| {
+        // Like curl, ignore the port and match subdomains too.
+        let entry = entry.split(':').next().unwrap_or(entry);
+        entry == "*" || host == entry
+            || host.strip_suffix(entry).is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+/// Read all the certificates from a PEM file.
+fn read_certificates(path: &PathBuf) -> Result<Vec<Certificate>, HttpError> {
+    const PEM_END: &str = "-----END CERTIFICATE-----";
+    let pem = fs::read_to_string(path).map_err(|e| HttpError::CaInfo(path.clone(), e))?;
+    let certs: Vec<_> = pem.split_inclusive(PEM_END)
+        .filter(|block| block.contains(PEM_END))
+        .map(|block| Certificate::from_pem(block.trim().as_bytes())
+            .map_err(|e| HttpError::Certificate(path.clone(), e)))
+        .collect::<Result<_, _>>()?;
+    if certs.is_empty() {
+        return Err(HttpError::CaInfo(path.clone(),
+            io::Error::new(io::ErrorKind::InvalidData, "no PEM certificates found")));
+    }
+    debug!("Trusting {} certificate(s) from {}", certs.len(), path.display());
+    Ok(certs)
+}
+
+
+/// Whether a response with given status is worth retrying the request.
+fn is_transient_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+/// Whether given error is worth retrying the request,
+/// i.e. it's a connection error or a timeout rather than e.g. an invalid URL.
+fn is_transient_error(error: &reqwest::Error) -> bool {
+    error.is_http() || error.get_ref().is_some_and(|e| e.is::<::std::io::Error>())
+}
+
+/// Delay requested by the `Retry-After` header of a 429 or 503 response,
+/// given either in seconds or as an HTTP date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    if !matches!(response.status().as_u16(), 429 | 503) {
+        return None;
+    }
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value.trim(), time::get_time())
+}
+
+fn parse_retry_after(value: &str, now: time::Timespec) -> Option<Duration> {
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = time::strptime(value, "%a, %d %b %Y %H:%M:%S GMT").ok()?.to_timespec();
+    Some(Duration::from_secs((date.sec - now.sec).max(0) as u64))
+}
+
+/// Random number between 0 and 1.
+fn jitter() -> f64 {
+    // Every RandomState is seeded differently, which is random enough for this.
+    let hash = RandomState::new().build_hasher().finish();
+    hash as f64 / u64::MAX as f64
+}
+
+
+/// Error that can occur while setting up the HTTP client.
+#[derive(Debug)]
+pub enum HttpError {
+    /// Invalid proxy URL.
+    Proxy(String),
+    /// Error reading the file with certificate authorities.
+    CaInfo(PathBuf, io::Error),
+    /// Invalid certificate in the file with certificate authorities.
+    Certificate(PathBuf, reqwest::Error),
+    /// Invalid value of the User-Agent header.
+    UserAgent(String),
+    /// Error creating the client itself (e.g. initializing TLS).
+    Client(reqwest::Error),
+}
+impl Error for HttpError {
+    fn description(&self) -> &str { "invalid HTTP settings" }
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            HttpError::CaInfo(_, ref e) => Some(e),
+            HttpError::Certificate(_, ref e) => Some(e),
+            HttpError::Client(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for HttpError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HttpError::Proxy(ref p) => write!(fmt, "invalid proxy URL `{}`", p),
+            HttpError::CaInfo(ref p, ref e) =>
+                write!(fmt, "cannot read certificates from {}: {}", p.display(), e),
+            HttpError::Certificate(ref p, ref e) =>
+                write!(fmt, "invalid certificate in {}: {}", p.display(), e),
+            HttpError::UserAgent(ref ua) => write!(fmt, "invalid user agent `{}`", ua),
+            HttpError::Client(ref e) => write!(fmt, "cannot create HTTP client: {}", e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use time::Timespec;
+    use test_server::{client, Response, TestServer};
+    use super::{bypasses_proxy, env_proxy, parse_no_proxy, parse_retry_after, Client, HttpConfig, Token};
+
+    #[test]
+    fn no_proxy() {
+        let no_proxy = parse_no_proxy("localhost, .example.com,10.0.0.1:8080,");
+        assert_eq!(vec!["localhost", "example.com", "10.0.0.1:8080"], no_proxy);
+        assert!(bypasses_proxy(&no_proxy, "localhost"));
+        assert!(bypasses_proxy(&no_proxy, "crates.EXAMPLE.com"));
+        assert!(bypasses_proxy(&no_proxy, "10.0.0.1"));
+        assert!(!bypasses_proxy(&no_proxy, "notexample.com"));
+        assert!(!bypasses_proxy(&no_proxy, "index.crates.io"));
+        assert!(bypasses_proxy(&parse_no_proxy("*"), "index.crates.io"));
+    }
+
+    #[test]
+    fn proxy() {
+        // The "proxy" gets requests for the full URLs.
+        let server = TestServer::serve(vec![Response::ok("proxied")]);
+        let config = HttpConfig{http_proxy: Some(server.url()), ..HttpConfig::default()};
+        let client = Client::new(&config, 0).unwrap();
+        let mut response = client.get("http://index.example.com/config.json", None).unwrap();
+        assert_eq!("proxied", response.text().unwrap());
+        assert_eq!("http://index.example.com/config.json", server.requested_paths()[0]);
+
+        // A proxy for HTTPS isn't used for plain HTTP.
+        let proxy = TestServer::serve(vec![]);
+        let server = TestServer::serve(vec![Response::ok("direct")]);
+        let config = HttpConfig{https_proxy: Some(proxy.url()), ..HttpConfig::default()};
+        let client = Client::new(&config, 0).unwrap();
+        let mut response = client.get(&format!("{}/config.json", server.url()), None).unwrap();
+        assert_eq!("direct", response.text().unwrap());
+
+        let config = HttpConfig{https_proxy: Some("not a url".into()), ..HttpConfig::default()};
+        assert!(Client::new(&config, 0).is_err());
+    }
+
+    #[test]
+    fn env_proxies() {
+        let vars = |name: &str| match name {
+            "HTTPS_PROXY" => Some("https-proxy:3128".to_owned()),
+            "HTTP_PROXY" => Some("evil:3128".to_owned()),
+            "all_proxy" => Some("all-proxy:3128".to_owned()),
+            _ => None,
+        };
+        assert_eq!(Some("https-proxy:3128".to_owned()), env_proxy("https", vars));
+        assert_eq!(Some("all-proxy:3128".to_owned()), env_proxy("http", vars));
+        assert_eq!(None, env_proxy("http", |_| None));
+    }
+
+    #[test]
+    fn retry_after_values() {
+        let now = Timespec::new(1445412480, 0);  // Wed, 21 Oct 2015 07:28:00 GMT
+        assert_eq!(Some(Duration::from_secs(120)), parse_retry_after("120", now));
+        assert_eq!(Some(Duration::from_secs(60)),
+            parse_retry_after("Wed, 21 Oct 2015 07:29:00 GMT", now));
+        assert_eq!(Some(Duration::from_secs(0)),
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now));
+        assert_eq!(None, parse_retry_after("soon", now));
+    }
+
+    #[test]
+    fn retries() {
+        let server = TestServer::serve(vec![
+            Response::new(503, "").with_header("Retry-After", "0"),
+            Response::new(429, ""),
+            Response::new(500, ""),
+            Response::ok("foo"),
+        ]);
+        let mut response = client(3).get(&format!("{}/foo", server.url()), None).unwrap();
+        assert_eq!("foo", response.text().unwrap());
+        assert_eq!(4, server.requests().len());
+
+        // Failures that aren't transient are returned right away,
+        // and so is the last failure once the retries run out.
+        let server = TestServer::serve(vec![
+            Response::new(404, ""),
+            Response::new(502, ""),
+            Response::new(502, ""),
+        ]);
+        let url = format!("{}/foo", server.url());
+        assert_eq!(404, client(3).get(&url, None).unwrap().status().as_u16());
+        assert_eq!(502, client(1).get(&url, None).unwrap().status().as_u16());
+        assert_eq!(3, server.requests().len());
+    }
+
+    #[test]
+    fn timeout() {
+        let config = HttpConfig{timeout: Some(Duration::from_millis(300)), ..HttpConfig::default()};
+        let client = Client::new(&config, 0).unwrap();
+
+        // A body that takes longer than that in total is fine, as long as it keeps coming.
+        let server = TestServer::serve(vec![
+            Response::ok("slow body").with_delay(Duration::from_millis(50)),
+            Response::ok("stalled").with_delay(Duration::from_millis(600)),
+        ]);
+        let mut response = client.get(&format!("{}/slow", server.url()), None).unwrap();
+        assert_eq!("slow body", response.text().unwrap());
+        let mut response = client.get(&format!("{}/stalled", server.url()), None).unwrap();
+        assert!(response.text().is_err());
+    }
+
+    #[test]
+    fn tokens() {
+        let token = Token::new("secret", "sparse+https://crates.example.com/index/").unwrap();
+        assert!(token.is_for("https://crates.example.com/api/v1/crates/foo"));
+        assert!(token.is_for("https://CRATES.example.com:443/index/3/f/foo"));
+        assert!(!token.is_for("http://crates.example.com/index/3/f/foo"));
+        assert!(!token.is_for("https://crates.example.com:8443/index/3/f/foo"));
+        assert!(!token.is_for("https://example.com/"));
+        assert!(Token::new("new\nline", "https://crates.example.com/").is_none());
+        assert_eq!("Token(<redacted>)", format!("{:?}", token));
+    }
+
+    #[test]
+    fn redirects() {
+        let other = TestServer::serve(vec![Response::ok("moved")]);
+        let server = TestServer::serve(vec![
+            Response::new(302, "").with_header("Location", "/moved"),
+            Response::new(302, "").with_header("Location", &format!("{}/elsewhere", other.url())),
+        ]);
+        let token = Token::new("secret", &format!("sparse+{}/", server.url())).unwrap();
+        let mut response = client(0)
+            .get(&format!("{}/foo", server.url()), Some(&token)).unwrap();
+        assert_eq!("moved", response.text().unwrap());
+
+        // The token is only sent to the registry's host, even when redirected.
+        let requests = server.requests();
+        assert_eq!(vec!["/foo", "/moved"], server.requested_paths());
+        assert!(requests.iter().all(|r| r.header("Authorization") == Some("secret")));
+        assert_eq!(vec!["/elsewhere"], other.requested_paths());
+        assert_eq!(None, other.requests()[0].header("Authorization"));
+    }
+}
@@ -0,0 +1,507 @@
+//! Module for looking up crate releases in a registry.
+//!
+//! Releases can be read either from the registry index
+//! (https://doc.rust-lang.org/cargo/reference/registry-index.html),
+//! which is what Cargo itself uses, or from the registry's web API.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::str::{self, FromStr};
+
+use semver::{Version, VersionReq};
+use semver_parser::range::{self, Op, Predicate};
+use serde_json::{self, Value as Json};
+
+use http::Client;
+use registry::{index_prefix, is_valid_crate_name, Registry, RegistryError};
+
+
+/// Where to look up the releases of a crate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VersionSource {
+    /// Registry index (the sparse protocol or a local checkout).
+    #[default]
+    Index,
+    /// Registry's web API.
+    Api,
+}
+impl FromStr for VersionSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "index" => Ok(VersionSource::Index),
+            "api" => Ok(VersionSource::Api),
+            _ => Err(format!("unknown version source `{}`", s)),
+        }
+    }
+}
+impl fmt::Display for VersionSource {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VersionSource::Index => write!(fmt, "index"),
+            VersionSource::Api => write!(fmt, "api"),
+        }
+    }
+}
+
+
+/// Single release (version) of a crate.
+///
+/// Field names follow the format of registry index entries.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct Release {
+    /// Name of the crate.
+    pub name: String,
+    /// Version of this release.
+    pub vers: Version,
+    /// Dependencies of this release.
+    /// Only available when the release comes from the index.
+    #[serde(default)]
+    pub deps: Vec<Dependency>,
+    /// SHA256 checksum of the crate archive, as a hex string.
+    pub cksum: String,
+    /// Features defined by the crate.
+    #[serde(default)]
+    pub features: BTreeMap<String, Vec<String>>,
+    /// Whether the release has been yanked.
+    #[serde(default)]
+    pub yanked: bool,
+    /// Minimum supported Rust version, if specified.
+    #[serde(default)]
+    pub rust_version: Option<String>,
+    /// Time the release was published at.
+    /// Only available when the release comes from the web API.
+    #[serde(default)]
+    pub created_at: Option<String>,
+    /// Size of the crate archive in bytes.
+    /// Only available when the release comes from the web API.
+    #[serde(default)]
+    pub crate_size: Option<u64>,
+}
+
+/// Dependency of a crate release, as described in the registry index.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct Dependency {
+    /// Name of the dependency, possibly renamed (see `package`).
+    pub name: String,
+    /// Version requirement of the dependency.
+    pub req: VersionReq,
+    /// Features enabled for the dependency.
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Whether it's an optional dependency.
+    #[serde(default)]
+    pub optional: bool,
+    /// Whether the default features of the dependency are enabled.
+    #[serde(default = "default_true")]
+    pub default_features: bool,
+    /// Target platform the dependency is limited to, if any.
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Kind of the dependency ("normal", "build", or "dev").
+    #[serde(default)]
+    pub kind: Option<String>,
+    /// Index URL of the registry the dependency comes from, if not the same one.
+    #[serde(default)]
+    pub registry: Option<String>,
+    /// Actual name of the dependency crate, if it has been renamed.
+    #[serde(default)]
+    pub package: Option<String>,
+}
+
+fn default_true() -> bool { true }
+
+
+/// Fetch all releases of given crate from the registry.
+pub fn get_releases(client: Option<&Client>, registry: &Registry, name: &str,
+                    source: VersionSource) -> Result<Vec<Release>, IndexError> {
+    match source {
+        VersionSource::Index => get_releases_from_index(client, registry, name),
+        VersionSource::Api => get_releases_from_api(client, registry, name),
+    }
+}
+
+/// Which releases can be picked when matching a version requirement.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Candidates {
+    /// Whether yanked releases are considered.
+    pub yanked: bool,
+    /// Whether pre-releases are considered for any version requirement.
+    pub prerelease: bool,
+}
+
+/// Check whether given version satisfies the requirement.
+///
+/// By default, pre-release versions only match requirements that explicitly
+/// mention a pre-release of the same version (like ">=1.0.0-beta"),
+/// so that e.g. "*" means the newest stable version.
+/// If `allow_prerelease` is true, a pre-release matches whenever it satisfies
+/// all the comparators of the requirement, with pre-releases ordered before
+/// their stable version (e.g. 1.1.0-alpha matches "^1.0", but 1.0.0-alpha doesn't).
+pub fn version_matches(req: &VersionReq, version: &Version, allow_prerelease: bool) -> bool {
+    if !version.is_prerelease() {
+        return req.matches(version);
+    }
+    if !allow_prerelease {
+        // VersionReq::matches already applies the rule, except for an empty requirement.
+        return *req != VersionReq::any() && req.matches(version);
+    }
+    // VersionReq doesn't expose its comparators, so they're parsed again from its string form.
+    let mut comparators = match range::parse(&req.to_string()) {
+        Ok(comparators) => comparators,
+        Err(e) => {
+            warn!("Failed to match pre-release {} against `{}`: {}", version, req, e);
+            return false;
+        }
+    };
+    // Comparators without a pre-release tag treat a pre-release at their lower bound
+    // like its stable version, even though it comes before it (1.0.0-alpha < 1.0.0).
+    let stable = (version.major, version.minor, version.patch);
+    if comparators.predicates.iter().any(|c| c.pre.is_empty() && lower_bound(c) == Some(stable)) {
+        return false;
+    }
+    // Otherwise, the only thing stopping semver from matching a pre-release is the rule above,
+    // and a comparator for exactly this version satisfies it.
+    match range::parse(&format!("={}", version)) {
+        Ok(exact) => comparators.predicates.extend(exact.predicates),
+        Err(e) => {
+            warn!("Failed to match pre-release {} against `{}`: {}", version, req, e);
+            return false;
+        }
+    }
+    VersionReq::from(comparators).matches(version)
+}
+
+/// Smallest stable version that satisfies the comparator, if it has a lower bound
+/// that semver's matching doesn't already compare pre-releases against.
+fn lower_bound(comparator: &Predicate) -> Option<(u64, u64, u64)> {
+    match (&comparator.op, comparator.minor, comparator.patch) {
+        (&Op::Gt, None, _) => Some((comparator.major + 1, 0, 0)),
+        (&Op::Gt, Some(minor), None) => Some((comparator.major, minor + 1, 0)),
+        (&Op::Gt, Some(_), Some(_)) | (&Op::Lt, _, _) | (&Op::LtEq, _, _) => None,
+        (_, minor, patch) => Some((comparator.major, minor.unwrap_or(0), patch.unwrap_or(0))),
+    }
+}
+
+/// Find the newest release that satisfies given version requirement
+/// and is one of the allowed `candidates`.
+pub fn newest_matching<'r>(releases: &'r [Release], req: &VersionReq,
+                           candidates: Candidates) -> Option<&'r Release> {
+    releases.iter()
+        .filter(|r| version_matches(req, &r.vers, candidates.prerelease))
+        .filter(|r| {
+            if r.yanked && !candidates.yanked {
+                trace!("Skipping yanked version {} of crate `{}`", r.vers, r.name);
+                return false;
+            }
+            true
+        })
+        .max_by(|a, b| a.vers.cmp(&b.vers))
+}
+
+/// Find the newest release that satisfies given version requirement,
+/// or explain why there isn't one.
+pub fn resolve<'r>(releases: &'r [Release], req: &VersionReq,
+                   candidates: Candidates) -> Result<&'r Release, IndexError> {
+    if let Some(release) = newest_matching(releases, req, candidates) {
+        return Ok(release);
+    }
+    let yanked = releases.iter()
+        .filter(|r| r.yanked && version_matches(req, &r.vers, candidates.prerelease))
+        .count();
+    let prerelease = if candidates.prerelease { None } else {
+        let all = Candidates{prerelease: true, ..candidates};
+        newest_matching(releases, req, all)
+            .filter(|r| r.vers.is_prerelease())
+            .map(|r| r.vers.clone())
+    };
+    Err(IndexError::NoMatch{yanked, prerelease})
+}
+
+
+/// Read the releases of given crate from its registry index file.
+fn get_releases_from_index(client: Option<&Client>, registry: &Registry,
+                           name: &str) -> Result<Vec<Release>, IndexError> {
+    let name = name.to_lowercase();
+    // Names of dependencies come from the index, so they may be invalid, too.
+    if !is_valid_crate_name(&name) {
+        return Err(IndexError::NotFound(name));
+    }
+    let path = format!("{}/{}", index_prefix(&name), name);
+    debug!("Reading releases of crate `{}` from index file {}", name, path);
+    let content = registry.read_index_file(client, &path)?
+        .ok_or_else(|| IndexError::NotFound(name.clone()))?;
+    let content = str::from_utf8(&content)
+        .map_err(|e| IndexError::Malformed(e.to_string()))?;
+    Ok(parse_index_file(content))
+}
+
+/// Parse the content of an index file, i.e. newline-delimited JSON objects
+/// describing each release of a crate.
+fn parse_index_file(content: &str) -> Vec<Release> {
+    content.lines().filter(|l| !l.trim().is_empty()).filter_map(|line| {
+        parse_index_line(line).map_err(|e| {
+            warn!("Skipping malformed index entry ({}): {}", e, line);
+        }).ok()
+    }).collect()
+}
+
+fn parse_index_line(line: &str) -> Result<Release, serde_json::Error> {
+    let json: Json = serde_json::from_str(line)?;
+    let mut release: Release = serde_json::from_value(json.clone())?;
+    // Features using the newer syntax (like "dep:foo") are stored separately.
+    if let Some(features2) = json.get("features2") {
+        let features2: BTreeMap<String, Vec<String>> = serde_json::from_value(features2.clone())?;
+        release.features.extend(features2);
+    }
+    Ok(release)
+}
+
+/// Fetch the releases of given crate from the registry's web API.
+fn get_releases_from_api(client: Option<&Client>, registry: &Registry,
+                         name: &str) -> Result<Vec<Release>, IndexError> {
+    let versions_url = registry.versions_url(name)?;
+    let client = client.ok_or_else(|| RegistryError::Offline(versions_url.clone()))?;
+    debug!("Fetching releases of crate `{}` from {}", name, versions_url);
+    let mut response = client.get(&versions_url, registry.token()).map_err(RegistryError::Http)?;
+    if response.status().as_u16() == 404 {
+        return Err(IndexError::NotFound(name.to_owned()));
+    }
+    if !response.status().is_success() {
+        return Err(RegistryError::Status(versions_url, response.status().as_u16()).into());
+    }
+    let response: Json = response.json().map_err(RegistryError::Http)?;
+    parse_api_response(name, &response)
+}
+
+/// Parse the response of the API endpoint listing crate versions.
+fn parse_api_response(name: &str, response: &Json) -> Result<Vec<Release>, IndexError> {
+    let versions = response.pointer("/versions").and_then(|vs| vs.as_array())
+        .ok_or_else(|| IndexError::Malformed("no versions array in API response".into()))?;
+    Ok(versions.iter().filter_map(|v| {
+        parse_api_version(name, v).map_err(|e| {
+            warn!("Skipping malformed version in API response ({}): {}", e, v);
+        }).ok()
+    }).collect())
+}
+
+fn parse_api_version(name: &str, version: &Json) -> Result<Release, String> {
+    let field = |key: &str| version.get(key).and_then(|v| v.as_str())
+        .ok_or_else(|| format!("missing `{}`", key));
+    Ok(Release{
+        name: name.to_owned(),
+        vers: Version::parse(field("num")?).map_err(|e| e.to_string())?,
+        deps: vec![],
+        cksum: field("checksum")?.to_owned(),
+        features: version.get("features").cloned()
+            .map(serde_json::from_value).unwrap_or_else(|| Ok(BTreeMap::new()))
+            .map_err(|e| e.to_string())?,
+        yanked: version.get("yanked").and_then(|y| y.as_bool()).unwrap_or(false),
+        rust_version: field("rust_version").ok().map(|v| v.to_owned()),
+        created_at: field("created_at").ok().map(|c| c.to_owned()),
+        crate_size: version.get("crate_size").and_then(|s| s.as_u64()),
+    })
+}
+
+
+/// Error that can occur while looking up crate releases.
+#[derive(Debug)]
+pub enum IndexError {
+    /// Error talking to the registry.
+    Registry(RegistryError),
+    /// Crate of given name doesn't exist in the registry.
+    NotFound(String),
+    /// Response from the registry is malformed.
+    Malformed(String),
+    /// No release matches the version requirement.
+    NoMatch{
+        /// Number of yanked releases that would've matched.
+        yanked: usize,
+        /// Newest pre-release that would've matched, if any.
+        prerelease: Option<Version>,
+    },
+}
+impl From<RegistryError> for IndexError {
+    fn from(input: RegistryError) -> Self {
+        IndexError::Registry(input)
+    }
+}
+impl Error for IndexError {
+    fn description(&self) -> &str { "failed to look up crate releases" }
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            IndexError::Registry(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for IndexError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IndexError::Registry(ref e) => write!(fmt, "{}", e),
+            IndexError::NotFound(ref n) => write!(fmt, "crate `{}` not found", n),
+            IndexError::Malformed(ref e) => write!(fmt, "malformed registry response: {}", e),
+            IndexError::NoMatch{yanked, ref prerelease} => {
+                write!(fmt, "no matching version found")?;
+                if let Some(ref v) = *prerelease {
+                    write!(fmt, " (only pre-releases match, the newest being {}; ", v)?;
+                    write!(fmt, "pass --allow-prerelease to consider them)")?;
+                } else if yanked > 0 {
+                    write!(fmt, " ({} matching version(s) yanked; ", yanked)?;
+                    write!(fmt, "pass --allow-yanked to consider them)")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use semver::{Version, VersionReq};
+    use serde_json;
+    use test_server::{Response, TestServer};
+    use super::{get_releases, newest_matching, parse_api_response, parse_index_file, resolve,
+                version_matches, Candidates, IndexError, Release, VersionSource};
+
+    const INDEX_FILE: &str = concat!(
+        r#"{"name":"foo","vers":"0.1.0","deps":[],"cksum":"aa","features":{},"yanked":false}"#, "\n",
+        r#"{"name":"foo","vers":"0.2.0","deps":[{"name":"bar","req":"^1.0","features":[],"optional":true,"default_features":true,"target":null,"kind":"normal"}],"cksum":"bb","features":{"extra":["bar"]},"features2":{"dep":["dep:bar"]},"yanked":false,"rust_version":"1.60"}"#, "\n",
+        "not json\n",
+        r#"{"name":"foo","vers":"1.0.0","deps":[],"cksum":"cc","features":{},"yanked":true}"#, "\n",
+    );
+
+    #[test]
+    fn index_file() {
+        let releases = parse_index_file(INDEX_FILE);
+        assert_eq!(3, releases.len());
+
+        let release = &releases[1];
+        assert_eq!(Version::parse("0.2.0").unwrap(), release.vers);
+        assert_eq!("bb", release.cksum);
+        assert_eq!(Some("1.60".to_owned()), release.rust_version);
+        assert_eq!(2, release.features.len());
+        assert_eq!(1, release.deps.len());
+        assert!(release.deps[0].optional);
+        assert!(releases[2].yanked);
+    }
+
+    #[test]
+    fn newest() {
+        let releases = parse_index_file(INDEX_FILE);
+        let any = VersionReq::any();
+        let stable = Candidates::default();
+        assert_eq!("0.2.0", newest_matching(&releases, &any, stable).unwrap().vers.to_string());
+        let req = VersionReq::parse("^0.1").unwrap();
+        assert_eq!("0.1.0", newest_matching(&releases, &req, stable).unwrap().vers.to_string());
+        let req = VersionReq::parse("^2").unwrap();
+        assert!(newest_matching(&releases, &req, stable).is_none());
+    }
+
+    #[test]
+    fn yanked() {
+        let releases = parse_index_file(INDEX_FILE);
+        let any = VersionReq::any();
+        let yanked = Candidates{yanked: true, ..Candidates::default()};
+        assert_eq!("1.0.0", newest_matching(&releases, &any, yanked).unwrap().vers.to_string());
+        let req = VersionReq::parse("^1").unwrap();
+        assert!(newest_matching(&releases, &req, yanked).unwrap().yanked);
+        match resolve(&releases, &req, Candidates::default()) {
+            Err(IndexError::NoMatch{yanked: 1, prerelease: None}) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn prerelease_matching() {
+        let alpha = Version::parse("1.0.0-alpha.1").unwrap();
+        let matches = |req: &str, allow| {
+            version_matches(&VersionReq::parse(req).unwrap(), &alpha, allow)
+        };
+        assert!(!matches("*", false));
+        assert!(!matches("^1.0", false));
+        assert!(matches(">=1.0.0-alpha", false));
+        assert!(matches("*", true));
+        assert!(!matches("^1.0", true));
+        assert!(!matches(">=1.0.0", true));
+        assert!(!matches("^0.9", true));
+        assert!(matches(">=0.9, <2", true));
+        assert!(matches(">=1.0.0-alpha", true));
+        assert!(!matches(">1.0.0-alpha.1", true));
+        assert!(!matches(">=1.0", true));
+        assert!(!matches("1.*", true));
+        assert!(matches(">0.9", true));
+        let beta = Version::parse("1.1.0-beta").unwrap();
+        assert!(version_matches(&VersionReq::parse("^1.0").unwrap(), &beta, true));
+        assert!(!version_matches(&VersionReq::parse("~1.0").unwrap(), &beta, true));
+        assert!(version_matches(&VersionReq::any(), &Version::parse("0.9.0").unwrap(), false));
+    }
+
+    #[test]
+    fn prereleases() {
+        let release = |v: &str| Release{
+            name: "foo".into(), vers: Version::parse(v).unwrap(), deps: vec![],
+            cksum: "".into(), features: Default::default(), yanked: false,
+            rust_version: None, created_at: None, crate_size: None,
+        };
+        let releases = vec![release("0.9.0"), release("1.0.0-alpha.1"), release("1.0.0-alpha.2")];
+        let stable = Candidates::default();
+        let prerelease = Candidates{prerelease: true, ..stable};
+
+        let any = VersionReq::any();
+        assert_eq!("0.9.0", resolve(&releases, &any, stable).unwrap().vers.to_string());
+        assert_eq!("1.0.0-alpha.2", resolve(&releases, &any, prerelease).unwrap().vers.to_string());
+
+        let req = VersionReq::parse(">0.9").unwrap();
+        match resolve(&releases, &req, stable) {
+            Err(IndexError::NoMatch{prerelease: Some(ref v), ..}) =>
+                assert_eq!("1.0.0-alpha.2", v.to_string()),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // Pre-releases of 1.0.0 come before it.
+        let req = VersionReq::parse(">=1.0.0").unwrap();
+        match resolve(&releases, &req, prerelease) {
+            Err(IndexError::NoMatch{yanked: 0, prerelease: None}) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn api_response() {
+        let response = serde_json::from_str(r#"{"versions": [
+            {"num": "1.0.0", "checksum": "cc", "yanked": false, "rust_version": "1.31",
+             "created_at": "2019-01-02T03:04:05.123456+00:00", "crate_size": 12345},
+            {"num": "0.1.0", "checksum": "aa", "yanked": true},
+            {"num": "bogus", "checksum": "xx"}
+        ]}"#).unwrap();
+        let releases = parse_api_response("foo", &response).unwrap();
+        assert_eq!(2, releases.len());
+        assert_eq!("foo", releases[0].name);
+        assert_eq!(Some("1.31".to_owned()), releases[0].rust_version);
+        assert_eq!(Some(12345), releases[0].crate_size);
+        assert!(releases[1].yanked);
+    }
+
+    #[test]
+    fn sparse_index() {
+        let server = TestServer::serve(vec![
+            Response::ok(r#"{"dl": "http://localhost/dl"}"#),
+            Response::ok(INDEX_FILE),
+            Response::new(404, ""),
+        ]);
+        let (client, registry) = server.registry(0);
+        let releases = get_releases(Some(&client), &registry, "Foo", VersionSource::Index).unwrap();
+        assert_eq!(3, releases.len());
+        match get_releases(Some(&client), &registry, "nope", VersionSource::Index) {
+            Err(IndexError::NotFound(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(vec!["/config.json", "/3/f/foo", "/no/pe/nope"]
+                       .into_iter().map(String::from).collect::<Vec<_>>(),
+                   server.requested_paths());
+    }
+}
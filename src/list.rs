@@ -0,0 +1,284 @@
+//! Module for printing listings for the user.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use semver::Version;
+use serde_json;
+use time;
+
+use cache::CacheEntry;
+use deps::{DepKind, Graph};
+use extract::{FileEntry, FileKind};
+use index::Release;
+
+
+/// Print a table of given crate releases, in the order they're given.
+pub fn print_releases<W: Write>(output: &mut W, releases: &[&Release]) -> io::Result<()> {
+    let rows: Vec<[String; 5]> = releases.iter().map(|r| [
+        r.vers.to_string(),
+        if r.yanked { "yanked" } else { "" }.to_owned(),
+        r.created_at.as_ref().map(|d| d.chars().take(10).collect())  // just the date part
+            .unwrap_or_else(|| "-".into()),
+        r.crate_size.map(format_size).unwrap_or_else(|| "-".into()),
+        r.rust_version.clone().unwrap_or_else(|| "-".into()),
+    ]).collect();
+
+    print_table(output, &["VERSION", "YANKED", "PUBLISHED", "SIZE", "MSRV"], &rows)
+}
+
+/// Print the entries of a crate archive as a table, followed by their totals.
+pub fn print_files<W: Write>(output: &mut W, entries: &[FileEntry]) -> io::Result<()> {
+    let rows: Vec<[String; 4]> = entries.iter().map(|e| [
+        format!("{:04o}", e.mode),
+        e.kind.to_string(),
+        e.size.to_string(),
+        match e.link {
+            Some(ref link) => format!("{} -> {}", e.path, link),
+            None => e.path.clone(),
+        },
+    ]).collect();
+    print_table(output, &["MODE", "TYPE", "SIZE", "PATH"], &rows)?;
+
+    let totals = Totals::of(entries);
+    writeln!(output, "\n{}: {}, {}, {}; {} in total",
+        count(totals.entries, "entry", "entries"),
+        count(totals.files, "file", "files"),
+        count(totals.dirs, "directory", "directories"),
+        count(totals.symlinks, "symlink", "symlinks"),
+        format_size(totals.size))
+}
+
+/// Print the entries of a crate archive as JSON, along with their totals.
+pub fn print_files_json<W: Write>(output: &mut W, name: &str, version: &Version,
+                                  entries: &[FileEntry]) -> io::Result<()> {
+    #[derive(Serialize)]
+    struct Listing<'a> {
+        #[serde(rename = "crate")]
+        name: &'a str,
+        version: String,
+        files: &'a [FileEntry],
+        totals: Totals,
+    }
+    let listing = Listing{
+        name,
+        version: version.to_string(),
+        files: entries,
+        totals: Totals::of(entries),
+    };
+    serde_json::to_writer_pretty(&mut *output, &listing)?;
+    writeln!(output)
+}
+
+/// Print a table of the archives in the download cache at `dir`, followed by their total size.
+pub fn print_cache<W: Write>(output: &mut W, dir: &Path, entries: &[CacheEntry]) -> io::Result<()> {
+    if entries.is_empty() {
+        return writeln!(output, "No crates cached in {}", dir.display());
+    }
+    let rows: Vec<[String; 5]> = entries.iter().map(|e| [
+        e.name.clone(),
+        e.version.to_string(),
+        format_size(e.size),
+        format_date(e.last_used.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)),
+        e.registry.clone(),
+    ]).collect();
+    print_table(output, &["CRATE", "VERSION", "SIZE", "LAST USED", "REGISTRY"], &rows)?;
+
+    let size = entries.iter().map(|e| e.size).sum();
+    writeln!(output, "\n{} in {}, {} in total",
+        count(entries.len(), "crate archive", "crate archives"), dir.display(), format_size(size))
+}
+
+/// Format a Unix timestamp as a UTC date.
+fn format_date(timestamp: u64) -> String {
+    let tm = time::at_utc(time::Timespec::new(timestamp as i64, 0));
+    time::strftime("%Y-%m-%d", &tm).unwrap()
+}
+
+/// Totals of the entries of a crate archive.
+#[derive(Debug, Default, Serialize)]
+struct Totals {
+    entries: usize,
+    files: usize,
+    dirs: usize,
+    symlinks: usize,
+    /// Total size of the files' content in bytes.
+    size: u64,
+}
+
+impl Totals {
+    fn of(entries: &[FileEntry]) -> Self {
+        let mut totals = Totals{entries: entries.len(), ..Totals::default()};
+        for entry in entries {
+            match entry.kind {
+                FileKind::File => totals.files += 1,
+                FileKind::Dir => totals.dirs += 1,
+                FileKind::Symlink => totals.symlinks += 1,
+                _ => {}
+            }
+            totals.size += entry.size;
+        }
+        totals
+    }
+}
+
+fn count(n: usize, singular: &str, plural: &str) -> String {
+    format!("{} {}", n, if n == 1 { singular } else { plural })
+}
+
+/// Print rows of cells as a table with given header, with columns aligned to the left.
+fn print_table<W: Write, R: AsRef<[String]>>(output: &mut W, header: &[&str],
+                                             rows: &[R]) -> io::Result<()> {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (i, cell) in row.as_ref().iter().enumerate() {
+            widths[i] = widths[i].max(cell.len());
+        }
+    }
+
+    let header: Vec<String> = header.iter().map(|h| h.to_string()).collect();
+    for row in Some(&header[..]).into_iter().chain(rows.iter().map(|r| r.as_ref())) {
+        let line: Vec<String> = row.iter().zip(&widths)
+            .map(|(cell, &w)| format!("{:<w$}", cell, w = w))
+            .collect();
+        writeln!(output, "{}", line.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+/// Print the dependency tree of given graph's root crates, like `cargo tree` does.
+///
+/// Crates whose dependencies have already been shown are marked with "(*)".
+pub fn print_tree<W: Write>(output: &mut W, graph: &Graph) -> io::Result<()> {
+    let mut shown = vec![false; graph.nodes().len()];
+    for &root in graph.roots() {
+        print_subtree(output, graph, root, None, &mut String::new(), &mut shown)?;
+    }
+    Ok(())
+}
+
+fn print_subtree<W: Write>(output: &mut W, graph: &Graph, id: usize, kind: Option<DepKind>,
+                           prefix: &mut String, shown: &mut [bool]) -> io::Result<()> {
+    let node = graph.node(id);
+    let kind = match kind {
+        Some(DepKind::Normal) | None => String::new(),
+        Some(k) => format!(" ({})", k),
+    };
+    let repeated = shown[id] && !node.deps.is_empty();
+    writeln!(output, "{} v{}{}{}", node.release.name, node.release.vers, kind,
+        if repeated { " (*)" } else { "" })?;
+    if repeated {
+        return Ok(());
+    }
+    shown[id] = true;
+
+    for (i, &(dep, kind)) in node.deps.iter().enumerate() {
+        let last = i + 1 == node.deps.len();
+        write!(output, "{}{}", prefix, if last { "└── " } else { "├── " })?;
+        let len = prefix.len();
+        prefix.push_str(if last { "    " } else { "│   " });
+        print_subtree(output, graph, dep, Some(kind), prefix, shown)?;
+        prefix.truncate(len);
+    }
+    Ok(())
+}
+
+/// Format a size in bytes in a human-readable way.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use semver::Version;
+    use extract::{FileEntry, FileKind};
+    use index::Release;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, UNIX_EPOCH};
+    use cache::CacheEntry;
+    use super::{format_size, print_cache, print_files, print_releases};
+
+    #[test]
+    fn sizes() {
+        assert_eq!("0 B", format_size(0));
+        assert_eq!("1023 B", format_size(1023));
+        assert_eq!("1.0 KiB", format_size(1024));
+        assert_eq!("1.5 MiB", format_size(3 * 512 * 1024));
+    }
+
+    #[test]
+    fn releases_table() {
+        let release = |v: &str, yanked, size| Release{
+            name: "foo".into(), vers: Version::parse(v).unwrap(), deps: vec![],
+            cksum: "".into(), features: Default::default(), yanked,
+            rust_version: Some("1.60".into()),
+            created_at: Some("2023-04-05T06:07:08.000000+00:00".into()),
+            crate_size: size,
+        };
+        let releases = [release("1.10.0", false, Some(2048)), release("1.9.0", true, None)];
+        let mut output = vec![];
+        print_releases(&mut output, &releases.iter().collect::<Vec<_>>()).unwrap();
+        assert_eq!(concat!(
+            "VERSION  YANKED  PUBLISHED   SIZE     MSRV\n",
+            "1.10.0           2023-04-05  2.0 KiB  1.60\n",
+            "1.9.0    yanked  2023-04-05  -        1.60\n",
+        ), String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn files_table() {
+        let entry = |path: &str, kind, size, mode, link: Option<&str>| FileEntry{
+            path: path.into(), kind, size, mode, link: link.map(|l| l.into()),
+        };
+        let entries = [
+            entry("foo-1.0.0/", FileKind::Dir, 0, 0o755, None),
+            entry("foo-1.0.0/src/lib.rs", FileKind::File, 2048, 0o644, None),
+            entry("foo-1.0.0/src/link.rs", FileKind::Symlink, 0, 0o777, Some("lib.rs")),
+        ];
+        let mut output = vec![];
+        print_files(&mut output, &entries).unwrap();
+        assert_eq!(concat!(
+            "MODE  TYPE     SIZE  PATH\n",
+            "0755  dir      0     foo-1.0.0/\n",
+            "0644  file     2048  foo-1.0.0/src/lib.rs\n",
+            "0777  symlink  0     foo-1.0.0/src/link.rs -> lib.rs\n",
+            "\n",
+            "3 entries: 1 file, 1 directory, 1 symlink; 2.0 KiB in total\n",
+        ), String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn cache_table() {
+        let entry = |name: &str, version: &str, size| CacheEntry{
+            path: PathBuf::new(),
+            registry: "crates-io-0123456789abcdef".into(),
+            name: name.into(),
+            version: Version::parse(version).unwrap(),
+            checksum: "abcd".into(),
+            size,
+            last_used: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+        let mut output = vec![];
+        print_cache(&mut output, Path::new("/cache"),
+            &[entry("foo", "1.0.0", 1536), entry("serde", "1.0.200", 100)]).unwrap();
+        assert_eq!(concat!(
+            "CRATE  VERSION  SIZE     LAST USED   REGISTRY\n",
+            "foo    1.0.0    1.5 KiB  2023-11-14  crates-io-0123456789abcdef\n",
+            "serde  1.0.200  100 B    2023-11-14  crates-io-0123456789abcdef\n",
+            "\n",
+            "2 crate archives in /cache, 1.6 KiB in total\n",
+        ), String::from_utf8(output).unwrap());
+    }
+}
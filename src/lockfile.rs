@@ -0,0 +1,211 @@
+//! Module for reading Cargo.lock files.
+//!
+//! Both the current format (with checksums stored alongside packages)
+//! and the legacy one (with checksums in the `[metadata]` table) are supported.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use semver::Version;
+use toml;
+
+
+/// Packages pinned in a Cargo.lock file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Lockfile {
+    packages: Vec<Package>,
+}
+
+impl Lockfile {
+    /// Load the lockfile from given path.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LockfileError> {
+        let path = path.as_ref();
+        trace!("Reading lockfile {}", path.display());
+        let content = fs::read_to_string(path)
+            .map_err(|e| LockfileError::Io(path.to_owned(), e))?;
+        Lockfile::parse(&content).map_err(|e| LockfileError::Parse(path.to_owned(), e))
+    }
+
+    /// Parse the lockfile from its content.
+    pub fn parse(content: &str) -> Result<Self, toml::de::Error> {
+        let raw: RawLockfile = toml::from_str(content)?;
+        let mut packages = raw.packages;
+        // Legacy lockfiles keep checksums in entries like:
+        // "checksum $NAME $VERSION ($SOURCE)" = "$SHA256"
+        for package in &mut packages {
+            if package.checksum.is_some() {
+                continue;
+            }
+            if let Some(ref source) = package.source {
+                let key = format!("checksum {} {} ({})", package.name, package.version, source);
+                package.checksum = raw.metadata.get(&key)
+                    .filter(|c| c.as_str() != "<none>")
+                    .cloned();
+            }
+        }
+        debug!("Lockfile pins {} package(s)", packages.len());
+        Ok(Lockfile{packages})
+    }
+
+    #[inline]
+    pub fn packages(&self) -> &[Package] {
+        &self.packages
+    }
+}
+
+/// Lockfile as it's deserialized from TOML.
+#[derive(Deserialize)]
+struct RawLockfile {
+    #[serde(default, rename = "package")]
+    packages: Vec<Package>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+}
+
+
+/// A single `[[package]]` entry of the lockfile.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Package {
+    pub name: String,
+    pub version: Version,
+    /// Where the package comes from, e.g. "registry+https://github.com/rust-lang/crates.io-index".
+    /// Missing for path dependencies and workspace members.
+    pub source: Option<String>,
+    /// SHA256 checksum of the package's archive (only for registry packages).
+    pub checksum: Option<String>,
+}
+
+impl Package {
+    /// Kind of the package's source.
+    pub fn source_kind(&self) -> SourceKind<'_> {
+        match self.source {
+            None => SourceKind::Path,
+            Some(ref s) => if let Some(index) = s.strip_prefix("registry+") {
+                SourceKind::Registry(index)
+            } else if s.starts_with("sparse+") {
+                SourceKind::Registry(s)
+            } else {
+                SourceKind::Git
+            },
+        }
+    }
+}
+
+/// Kind of a lockfile package's source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceKind<'p> {
+    /// Package from a registry with given index URL.
+    Registry(&'p str),
+    /// Package from a git repository.
+    Git,
+    /// Local package (path dependency or workspace member).
+    Path,
+}
+
+impl<'p> fmt::Display for SourceKind<'p> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SourceKind::Registry(index) => write!(fmt, "registry {}", index),
+            SourceKind::Git => write!(fmt, "git"),
+            SourceKind::Path => write!(fmt, "path"),
+        }
+    }
+}
+
+
+/// Error that can occur while loading a lockfile.
+#[derive(Debug)]
+pub enum LockfileError {
+    /// Error reading the lockfile.
+    Io(PathBuf, io::Error),
+    /// Error parsing the lockfile.
+    Parse(PathBuf, toml::de::Error),
+}
+impl Error for LockfileError {
+    fn description(&self) -> &str { "invalid lockfile" }
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            LockfileError::Io(_, ref e) => Some(e),
+            LockfileError::Parse(_, ref e) => Some(e),
+        }
+    }
+}
+impl fmt::Display for LockfileError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LockfileError::Io(ref p, ref e) =>
+                write!(fmt, "cannot read lockfile {}: {}", p.display(), e),
+            LockfileError::Parse(ref p, ref e) =>
+                write!(fmt, "malformed lockfile {}: {}", p.display(), e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Lockfile, SourceKind};
+
+    const CRATES_IO: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+    #[test]
+    fn current_format() {
+        let lockfile = Lockfile::parse(&format!(r#"
+            version = 3
+
+            [[package]]
+            name = "app"
+            version = "0.1.0"
+            dependencies = ["foo", "bar"]
+
+            [[package]]
+            name = "foo"
+            version = "1.2.3"
+            source = "{}"
+            checksum = "abcd"
+
+            [[package]]
+            name = "bar"
+            version = "0.1.0-beta"
+            source = "git+https://example.com/bar.git#0123456789"
+        "#, CRATES_IO)).unwrap();
+
+        let packages = lockfile.packages();
+        assert_eq!(3, packages.len());
+        assert_eq!(SourceKind::Path, packages[0].source_kind());
+        assert_eq!(SourceKind::Registry("https://github.com/rust-lang/crates.io-index"),
+            packages[1].source_kind());
+        assert_eq!(Some("abcd"), packages[1].checksum.as_deref());
+        assert_eq!(SourceKind::Git, packages[2].source_kind());
+        assert_eq!("0.1.0-beta", packages[2].version.to_string());
+    }
+
+    #[test]
+    fn legacy_format() {
+        let lockfile = Lockfile::parse(&format!(r#"
+            [[package]]
+            name = "foo"
+            version = "1.2.3"
+            source = "{source}"
+
+            [[package]]
+            name = "baz"
+            version = "0.3.0"
+            source = "sparse+https://example.com/index/"
+
+            [metadata]
+            "checksum foo 1.2.3 ({source})" = "abcd"
+            "checksum baz 0.3.0 (sparse+https://example.com/index/)" = "<none>"
+        "#, source = CRATES_IO)).unwrap();
+
+        let packages = lockfile.packages();
+        assert_eq!(Some("abcd"), packages[0].checksum.as_deref());
+        assert_eq!(SourceKind::Registry("sparse+https://example.com/index/"),
+            packages[1].source_kind());
+        assert_eq!(None, packages[1].checksum);
+    }
+}
@@ -17,6 +17,8 @@ use slog_stdlog;
 use slog_stream;
 use time;
 
+use progress;
+
 
 // Default logging level defined using the two enums used by slog.
 // Both values must correspond to the same level. (This is checked by a test).
@@ -24,12 +26,12 @@ const DEFAULT_LEVEL: Level = Level::Info;
 const DEFAULT_FILTER_LEVEL: FilterLevel = FilterLevel::Info;
 
 // Arrays of log levels, indexed by verbosity.
-const POSITIVE_VERBOSITY_LEVELS: &'static [FilterLevel] = &[
+const POSITIVE_VERBOSITY_LEVELS: &[FilterLevel] = &[
     DEFAULT_FILTER_LEVEL,
     FilterLevel::Debug,
     FilterLevel::Trace,
 ];
-const NEGATIVE_VERBOSITY_LEVELS: &'static [FilterLevel] = &[
+const NEGATIVE_VERBOSITY_LEVELS: &[FilterLevel] = &[
     DEFAULT_FILTER_LEVEL,
     FilterLevel::Warning,
     FilterLevel::Error,
@@ -41,7 +43,7 @@ const NEGATIVE_VERBOSITY_LEVELS: &'static [FilterLevel] = &[
 /// Initialize logging with given verbosity.
 /// The verbosity value has the same meaning as in args::Options::verbosity.
 pub fn init(verbosity: isize) -> Result<(), SetLoggerError> {
-    let istty = cfg!(unix) && isatty::stderr_isatty();
+    let istty = stderr_isatty();
     let stderr = slog_stream::stream(io::stderr(), LogFormat{tty: istty});
 
     // Determine the log filtering level based on verbosity.
@@ -84,13 +86,18 @@ pub fn init(verbosity: isize) -> Result<(), SetLoggerError> {
     // Initialize the logger, possibly logging the excessive verbosity option.
     let env_logger_drain = builder.build();
     let logger = slog::Logger::root(env_logger_drain.fuse(), o!());
-    try!(slog_stdlog::set_logger(logger));
+    slog_stdlog::set_logger(logger)?;
     if excessive {
         warn!("-v/-q flag passed too many times, logging level {:?} assumed", level);
     }
     Ok(())
 }
 
+/// Whether stderr is a terminal, which gets fancier output.
+pub fn stderr_isatty() -> bool {
+    cfg!(unix) && isatty::stderr_isatty()
+}
+
 
 // Log formatting
 
@@ -101,7 +108,7 @@ struct LogFormat {
 
 impl slog_stream::Format for LogFormat {
     /// Format a single log Record and write it to given output.
-    fn format(&self, output: &mut io::Write,
+    fn format(&self, output: &mut dyn io::Write,
               record: &slog::Record,
               _logger_kvp: &slog::OwnedKeyValueList) -> io::Result<()> {
         // Format the higher level (more fine-grained) messages with greater detail,
@@ -136,7 +143,11 @@ impl slog_stream::Format for LogFormat {
             format!("{}: {}\n", level, record.msg())
         };
 
-        try!(output.write_all(msg.as_bytes()));
+        // Log messages go above the progress line, which is redrawn after them.
+        if self.tty && progress::take_line() {
+            output.write_all(progress::CLEAR_LINE.as_bytes())?;
+        }
+        output.write_all(msg.as_bytes())?;
         Ok(())
     }
 }
@@ -3,22 +3,31 @@
 //!
 
              extern crate ansi_term;
-#[macro_use] extern crate clap;
+             extern crate clap;
              extern crate conv;
 #[macro_use] extern crate derive_error;
              extern crate exitcode;
              extern crate flate2;
+             extern crate glob;
+             extern crate hex;
              extern crate isatty;
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate maplit;
              extern crate reqwest;
              extern crate semver;
+             extern crate semver_parser;
+             extern crate serde;
+#[macro_use] extern crate serde_derive;
              extern crate serde_json;
+             extern crate sha2;
              extern crate slog_envlogger;
              extern crate slog_stdlog;
              extern crate slog_stream;
+             extern crate strsim;
              extern crate time;
              extern crate tar;
+             extern crate tempfile;
+             extern crate toml;
 
 // `slog` must precede `log` in declarations here, because we want to simultaneously:
 // * use the standard `log` macros
@@ -28,23 +37,58 @@
 
 
 mod args;
+mod cache;
+mod cargo_index;
+mod config;
+mod credentials;
+mod deps;
+mod download;
+mod extract;
+mod http;
+mod index;
+mod list;
+mod lockfile;
 mod logging;
+mod message;
+mod progress;
+mod registry;
+#[cfg(test)]
+mod test_server;
 
 
-use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::fs;
-use std::io::{self, Read, Write};
+use std::io::{self, Seek, SeekFrom, Write};
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use exitcode::ExitCode;
 
 use log::LogLevel::*;
-use reqwest::header::CONTENT_LENGTH;
-use semver::Version;
-use serde_json::Value as Json;
 
-use args::{ArgsError, Crate, Output};
+use args::{ArgsError, CacheCommand, Crate, Format, Options, Output};
+use cache::Cache;
+use config::{cargo_home, Config};
+use deps::ResolveError;
+use download::{download_crate, DownloadError, Downloaded, LocalArchives};
+use extract::{extract_crate, list_entries, read_file, ExtractError};
+use http::{normalize_proxy, Client, HttpConfig};
+use index::{Candidates, Release, VersionSource};
+use lockfile::{Lockfile, SourceKind};
+use message::CrateMessage;
+use registry::Registry;
+
 
+/// Exit code used when a crate archive contains entries that are unsafe to extract.
+/// It's just past the range of the standard sysexits.h codes.
+const EXIT_UNSAFE_ARCHIVE: ExitCode = 79;
+/// Exit code used when a downloaded crate archive doesn't match its expected checksum.
+const EXIT_CHECKSUM_MISMATCH: ExitCode = 80;
 
 lazy_static! {
     /// Application / package name, as filled out by Cargo.
@@ -65,66 +109,444 @@ fn main() {
     logging::init(opts.verbosity).unwrap();
     log_signature();
 
-    let version = match opts.crate_.exact_version() {
-        Some(v) => {
-            debug!("Exact crate version given in arguments, not querying crates.io");
-            Cow::Borrowed(v)
+    let cache = if opts.no_cache {
+        None
+    } else {
+        let dir = opts.cache_dir.clone().or_else(Cache::default_dir);
+        if dir.is_none() {
+            warn!("Cannot determine where the download cache should be, not using it");
         }
-        None => Cow::Owned(get_newest_version(&opts.crate_).unwrap_or_else(|e| {
-            error!("Failed to get the newest version of crate {}: {}", opts.crate_, e);
-            exit(exitcode::TEMPFAIL);
-        })),
+        dir.map(Cache::new)
+    };
+    if let Some(ref command) = opts.cache_command {
+        let cache = cache.unwrap_or_else(|| {
+            error!("No download cache to manage (pass --cache-dir to specify it)");
+            exit(exitcode::CONFIG);
+        });
+        exit(run_cache_command(&cache, command));
+    }
+    // Archives that Cargo has downloaded itself are reused, too.
+    let cargo_cache = cargo_home().map(|h| h.join("registry").join("cache"))
+        .filter(|_| !opts.no_cache);
+    let local = LocalArchives{
+        cache: cache.as_ref(),
+        cargo_cache: cargo_cache.as_deref(),
+        partial: None,
     };
-    let crate_bytes = download_crate(&opts.crate_.name(), &version).unwrap_or_else(|e| {
-        error!("Failed to download crate `{}=={}`: {}", opts.crate_.name(), version, e);
-        exit(exitcode::TEMPFAIL);
+
+    let config = Config::load().unwrap_or_else(|e| {
+        error!("Failed to read Cargo configuration: {}", e);
+        exit(exitcode::CONFIG);
     });
 
-    if opts.extract {
-        // Extract to a directory named $CRATE-$VERSION
-        // Due to how crate archives are structured (they contain
-        // single top-level directory) this is done automatically
-        // if you simply extract them in $CWD.
-        let dir: PathBuf = format!("./{}-{}", opts.crate_.name(), version).into();
-        debug!("Extracting crate archive to {}/", dir.display());
-        let gzip = flate2::read::GzDecoder::new(&crate_bytes[..]).unwrap();
-        let mut archive = tar::Archive::new(gzip);
-        match archive.unpack(".") {
-            Ok(_) => {
-                // If -x option was passed, we need to move the extracted directory
-                // to wherever the user wanted.
-                let mut dir = dir;
-                if let Some(&Output::Path(ref p)) = opts.output.as_ref() {
-                    fs::rename(&dir, p).unwrap_or_else(|e| {
-                        error!("Failed to move extracted archive from {} to {}: {}",
-                            dir.display(), p.display(), e);
-                        exit(exitcode::IOERR)
-                    });
-                    dir = p.clone();
-                }
-                info!("Crate content extracted to {}/", dir.display());
+    // All the requests share a single HTTP client (and thus its connection pool).
+    // In offline mode, there is none, so that nothing can be fetched by accident.
+    let client = if opts.offline {
+        None
+    } else {
+        let mut http_config = HttpConfig::from_config(&config).unwrap_or_else(|e| {
+            error!("Failed to read HTTP settings from Cargo configuration: {}", e);
+            exit(exitcode::CONFIG);
+        });
+        if let Some(ref proxy) = opts.proxy {
+            http_config.http_proxy = Some(normalize_proxy(proxy));
+            http_config.https_proxy = Some(normalize_proxy(proxy));
+        }
+        if let Some(ref cacert) = opts.cacert {
+            http_config.cainfo = Some(cacert.clone());
+        }
+        Some(Client::new(&http_config, opts.retries).unwrap_or_else(|e| {
+            error!("Failed to set up HTTP client: {}", e);
+            exit(exitcode::CONFIG);
+        }))
+    };
+    let registry = match opts.registry {
+        Some(ref name) => {
+            let token = opts.token_file.as_ref().map(|path| {
+                let token = fs::read_to_string(path).unwrap_or_else(|e| {
+                    error!("Failed to read the registry token from {}: {}", path.display(), e);
+                    exit(exitcode::NOINPUT);
+                });
+                token.trim().to_owned()
+            });
+            Registry::from_config(client.as_ref(), name, &config, token).unwrap_or_else(|e| {
+                error!("Failed to set up registry `{}`: {}", name, e);
+                exit(exitcode::CONFIG);
+            })
+        }
+        None => Registry::crates_io(),
+    };
+    debug!("Using registry {}", registry);
+
+    if opts.list {
+        let source = opts.versions_from.unwrap_or(
+            if registry.has_api() && !opts.offline { VersionSource::Api } else { VersionSource::Index });
+        for (i, crate_) in opts.crates.iter().enumerate() {
+            if opts.crates.len() > 1 {
+                println!("{}{}:", if i > 0 { "\n" } else { "" }, crate_);
             }
-            Err(e) => {
-                error!("Couldn't extract crate to {}/: {}", dir.display(), e);
-                exit(exitcode::TEMPFAIL)
+            list_versions(client.as_ref(), &registry, crate_, source).unwrap_or_else(|e| {
+                error!("Failed to list versions of crate {}: {}", crate_, e);
+                exit(exitcode::TEMPFAIL);
+            });
+        }
+        return;
+    }
+
+    if !opts.verify {
+        warn!("Checksum verification disabled, the crate archive will not be checked for integrity");
+    }
+    let graph = if opts.recursive {
+        let candidates = Candidates{
+            yanked: opts.allow_yanked,
+            prerelease: opts.allow_prerelease,
+        };
+        let graph = deps::resolve(
+            client.as_ref(), &registry, &opts.crates, &opts.features, opts.dep_kinds, candidates,
+        ).unwrap_or_else(|e| {
+            error!("Failed to resolve dependencies: {}", e);
+            exit(match e {
+                ResolveError::Features(_) => exitcode::USAGE,
+                _ => exitcode::TEMPFAIL,
+            });
+        });
+        info!("Resolved {} crate(s) to download", graph.nodes().len());
+        Some(graph)
+    } else {
+        None
+    };
+
+    let jobs = match (&opts.lockfile, &graph) {
+        (Some(path), _) => lockfile_jobs(client.as_ref(), &registry, path).unwrap_or_else(|e| {
+            error!("Failed to read crates from lockfile {}: {}", path.display(), e);
+            exit(exitcode::DATAERR);
+        }),
+        (_, Some(graph)) => graph.nodes().iter().map(|n| Job{
+            registry: n.registry.clone(),
+            crate_: Crate::exact(&n.release.name, n.release.vers.clone()),
+            checksum: Some(n.release.cksum.clone()),
+            yanked: Some(n.release.yanked),
+        }).collect(),
+        _ => opts.crates.iter().map(|c| Job{
+            registry: registry.clone(),
+            crate_: c.clone(),
+            checksum: opts.checksum.clone(),
+            yanked: None,
+        }).collect(),
+    };
+    if opts.multiple() {
+        if let Some(Output::Path(ref dir)) = opts.output {
+            fs::create_dir_all(dir).unwrap_or_else(|e| {
+                error!("Failed to create output directory {}: {}", dir.display(), e);
+                exit(exitcode::CANTCREAT);
+            });
+        }
+    }
+
+    // Progress of the downloads is shown on the terminal, unless it'd be noise.
+    if logging::stderr_isatty() && !opts.quiet() {
+        progress::init(jobs.len());
+    }
+
+    // Process the crates on a pool of worker threads,
+    // each picking up the next job that hasn't been taken yet.
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; jobs.len()]);
+    thread::scope(|s| {
+        for _ in 0..opts.jobs.min(jobs.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let job = match jobs.get(i) {
+                    Some(j) => j,
+                    None => break,
+                };
+                let result = fetch_crate(client.as_ref(), local, job, &opts);
+                progress::crate_done();
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    progress::finish();
+
+    let results: Vec<_> = results.into_inner().unwrap().into_iter().map(|r| r.unwrap()).collect();
+    if opts.multiple() {
+        let failed: Vec<_> = jobs.iter().zip(&results)
+            .filter(|&(_, r)| r.is_err()).map(|(j, _)| j.crate_.to_string())
+            .collect();
+        info!("Downloaded {} of {} crates", results.len() - failed.len(), results.len());
+        if !failed.is_empty() {
+            error!("Failed to download crate(s): {}", failed.join(", "));
+        }
+    }
+    // In JSON mode, stdout is reserved for the messages.
+    if let (Some(ref graph), Format::Human) = (&graph, opts.message_format) {
+        list::print_tree(&mut io::stdout(), graph).unwrap();
+    }
+    if let Some(&Err(code)) = results.iter().find(|r| r.is_err()) {
+        exit(code);
+    }
+}
+
+
+/// A single crate to download.
+struct Job {
+    /// Registry to download the crate from.
+    registry: Registry,
+    crate_: Crate,
+    /// Expected SHA256 checksum of the crate's archive, if known upfront.
+    checksum: Option<String>,
+    /// Whether the crate's version has been yanked, if known upfront.
+    yanked: Option<bool>,
+}
+
+/// Create download jobs for all the registry packages pinned in given Cargo.lock.
+///
+/// Packages from `registry` are downloaded from it,
+/// while other registries are set up based on their index URL.
+fn lockfile_jobs(client: Option<&Client>, registry: &Registry,
+                 path: &Path) -> Result<Vec<Job>, Box<dyn Error>> {
+    let lockfile = Lockfile::load(path)?;
+    let mut registries: HashMap<&str, Registry> = HashMap::new();
+    let mut jobs = vec![];
+    for package in lockfile.packages() {
+        let index = match package.source_kind() {
+            SourceKind::Registry(index) => index,
+            kind => {
+                info!("Skipping {} package `{}=={}`", kind, package.name, package.version);
+                continue;
             }
+        };
+        let registry = match registries.entry(index) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(if registry.has_index(index) {
+                registry.clone()
+            } else {
+                Registry::from_source(client, index)?
+            }),
+        };
+        jobs.push(Job{
+            registry: registry.clone(),
+            crate_: Crate::exact(&package.name, package.version.clone()),
+            checksum: package.checksum.clone(),
+            yanked: None,
+        });
+    }
+    info!("Found {} registry package(s) in lockfile {}", jobs.len(), path.display());
+    Ok(jobs)
+}
+
+/// Resolve, download, and output a single crate.
+///
+/// Any failure is logged here, and the exit code it warrants is returned.
+fn fetch_crate(client: Option<&Client>, local: LocalArchives, job: &Job,
+               opts: &Options) -> Result<(), ExitCode> {
+    let (registry, crate_) = (&job.registry, &job.crate_);
+
+    // Checksum known upfront takes precedence over the one from the registry.
+    // If it's not given, we need to query the registry even for an exact version
+    // (unless we aren't verifying the archive and the download URL doesn't need it).
+    let needs_checksum = registry.needs_checksum() || (opts.verify && job.checksum.is_none());
+    let (version, checksum, yanked) = match crate_.exact_version() {
+        Some(v) if job.checksum.is_some() || !needs_checksum => {
+            debug!("Exact version of crate `{}` is known, not querying the registry",
+                crate_.name());
+            (v.clone(), job.checksum.clone(), job.yanked)
+        }
+        _ => {
+            let candidates = Candidates{
+                yanked: opts.allow_yanked,
+                prerelease: opts.allow_prerelease,
+            };
+            let release = get_newest_version(
+                client, registry, crate_, opts.versions_from.unwrap_or_default(), candidates,
+            ).map_err(|e| {
+                error!("Failed to get the newest version of crate {}: {}", crate_, e);
+                exitcode::TEMPFAIL
+            })?;
+            (release.vers, job.checksum.clone().or(Some(release.cksum)), Some(release.yanked))
+        }
+    };
+    let download_error = |e: DownloadError| {
+        error!("Failed to download crate `{}=={}` from registry `{}`: {}",
+            crate_.name(), version, registry.name(), e);
+        match e {
+            DownloadError::ChecksumMismatch{..} => EXIT_CHECKSUM_MISMATCH,
+            DownloadError::Offline => exitcode::UNAVAILABLE,
+            DownloadError::Save(..) => exitcode::IOERR,
+            _ => exitcode::TEMPFAIL,
+        }
+    };
+
+    // With --message-format json, every crate that ends up in a file or directory
+    // is described on stdout (which the archives themselves never go to then).
+    let report = |downloaded: &Downloaded, output_path: Option<&Path>, extract_dir: Option<&Path>| {
+        if opts.message_format != Format::Json {
+            return Ok(());
+        }
+        CrateMessage{
+            name: crate_.name(),
+            version: version.to_string(),
+            requirement: crate_.version_requirement().to_string(),
+            source: &downloaded.url,
+            size: downloaded.size,
+            sha256: &downloaded.sha256,
+            output_path,
+            extract_dir,
+            yanked,
+        }.print().map_err(|e| {
+            error!("Failed to output message about crate `{}=={}`: {}", crate_.name(), version, e);
+            exitcode::IOERR
+        })
+    };
+
+    // With more than one crate, the output (if given) is a directory
+    // and the archives are named after the crates they contain.
+    let multiple = opts.multiple();
+    let output_dir = match opts.output {
+        Some(Output::Path(ref p)) if multiple => p.clone(),
+        _ => PathBuf::from("."),
+    };
+    // Crate archives contain a single top-level directory named $CRATE-$VERSION,
+    // which ends up in the output directory unless an explicit path has been given.
+    let extract_dir = match (multiple, &opts.output) {
+        (false, Some(Output::Path(p))) => p.clone(),
+        _ => output_dir.join(format!("{}-{}", crate_.name(), version)),
+    };
+    let archive_name = format!("{}-{}.crate", crate_.name(), version);
+    let output = match opts.output {
+        _ if multiple => Output::Path(output_dir.join(archive_name)),
+        Some(ref output) => output.clone(),
+        None if opts.message_format == Format::Json => Output::Path(archive_name.into()),
+        None => Output::Stdout,
+    };
+
+    // Partial downloads are kept next to the output, so that they can be resumed
+    // by running the command again if they get interrupted.
+    let partial = if opts.cat.is_some() || opts.list_files {
+        None
+    } else if opts.extract {
+        part_path(&extract_dir, ".crate.part")
+    } else {
+        match output {
+            Output::Path(ref p) => part_path(p, ".part"),
+            Output::Stdout => None,
         }
+    };
+    let local = LocalArchives{partial: partial.as_deref(), ..local};
+    let mut download = download_crate(
+        client, registry, crate_.name(), &version, checksum.as_deref(), opts.verify, local,
+    ).map_err(download_error)?;
+
+    // The archive is streamed to its destination as it's being downloaded,
+    // so if its checksum turns out to be wrong, we have to discard it afterwards
+    // (extracted crates and saved archives are only moved to their destination
+    // once it's verified).
+    if let Some(ref path) = opts.cat {
+        // The file is kept aside until then, since it could be too large for memory.
+        let mut content = tempfile::tempfile().map_err(|e| {
+            error!("Failed to create a temporary file: {}", e);
+            exitcode::CANTCREAT
+        })?;
+        read_file(&mut download, crate_.name(), &version, path, &mut content).map_err(|e| {
+            error!("Couldn't read {} from crate `{}=={}`: {}",
+                path.display(), crate_.name(), version, e);
+            match e {
+                ExtractError::NotFound(..) | ExtractError::Link(..) => exitcode::NOINPUT,
+                _ => exitcode::TEMPFAIL,
+            }
+        })?;
+        // Don't print anything from an archive that fails verification.
+        download.finish().map_err(download_error)?;
+        let stdout = io::stdout();
+        content.seek(SeekFrom::Start(0))
+            .and_then(|_| io::copy(&mut content, &mut stdout.lock()))
+            .map_err(|e| {
+                error!("Failed to output {}: {}", path.display(), e);
+                exitcode::IOERR
+            })?;
+    } else if opts.list_files {
+        let entries = list_entries(&mut download).map_err(|e| {
+            error!("Couldn't read the archive of crate `{}=={}`: {}", crate_.name(), version, e);
+            exitcode::TEMPFAIL
+        })?;
+        download.finish().map_err(download_error)?;
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        match opts.format {
+            Format::Human => list::print_files(&mut stdout, &entries),
+            Format::Json => list::print_files_json(&mut stdout, crate_.name(), &version, &entries),
+        }.map_err(|e| {
+            error!("Failed to output the list of files: {}", e);
+            exitcode::IOERR
+        })?;
+    } else if opts.extract {
+        let dir = extract_dir;
+        let extract_error = |e: ExtractError| {
+            error!("Couldn't extract crate to {}/: {}", dir.display(), e);
+            match e {
+                ExtractError::Unsafe(_) => EXIT_UNSAFE_ARCHIVE,
+                ExtractError::Exists(_) => exitcode::CANTCREAT,
+                ExtractError::NotFound(..) | ExtractError::Link(..) => exitcode::NOINPUT,
+                ExtractError::Io(_) => exitcode::TEMPFAIL,
+            }
+        };
+        debug!("Extracting crate archive to {}/", dir.display());
+        let extracted = extract_crate(
+            &mut download, &dir, crate_.name(), &version, &opts.filter, opts.force,
+        ).map_err(extract_error)?;
+        let downloaded = download.finish().map_err(download_error)?;
+        extracted.persist(&dir, opts.force).map_err(extract_error)?;
+        info!("Crate content extracted to {}/", dir.display());
+        report(&downloaded, None, Some(&dir))?;
     } else {
-        let output = opts.output.as_ref().unwrap_or(&Output::Stdout);
         match output {
-            &Output::Stdout => { io::stdout().write(&crate_bytes).unwrap(); }
-            &Output::Path(ref p) => {
-                let mut file = fs::OpenOptions::new()
-                    .write(true).create(true)
-                    .open(p).unwrap_or_else(|e| {
-                        error!("Failed to open output file {}: {}", p.display(), e);
-                        exit(exitcode::IOERR)
-                    });
-                file.write(&crate_bytes).unwrap();
+            Output::Stdout => {
+                let stdout = io::stdout();
+                io::copy(&mut download, &mut stdout.lock()).map_err(|e| {
+                    error!("Failed to output crate's archive: {}", e);
+                    exitcode::IOERR
+                })?;
+                download.finish().map_err(download_error)?;
+            }
+            Output::Path(ref p) => {
+                // The archive only replaces the file once it's been verified.
+                let downloaded = download.save(p).map_err(download_error)?;
                 info!("Crate's archive written to {}", p.display());
+                report(&downloaded, Some(p), None)?;
             }
         }
     }
+    Ok(())
+}
+
+/// Path of the file that a partial download is kept in, next to given output path.
+fn part_path(output: &Path, suffix: &str) -> Option<PathBuf> {
+    let mut name = output.file_name()?.to_owned();
+    name.push(suffix);
+    Some(output.with_file_name(name))
+}
+
+/// Run a `cache` subcommand, returning the exit code.
+fn run_cache_command(cache: &Cache, command: &CacheCommand) -> ExitCode {
+    let cache_error = |e: io::Error| {
+        error!("Failed to read the download cache in {}: {}", cache.dir().display(), e);
+        exitcode::IOERR
+    };
+    let result = match *command {
+        CacheCommand::List => cache.entries().map_err(cache_error).and_then(|entries| {
+            list::print_cache(&mut io::stdout(), cache.dir(), &entries).map_err(|e| {
+                error!("Failed to output the list of cached crates: {}", e);
+                exitcode::IOERR
+            })
+        }),
+        CacheCommand::Prune{older_than} => cache.prune(older_than).map_err(cache_error)
+            .map(|removed| {
+                let size = removed.iter().map(|e| e.size).sum();
+                info!("Removed {} cached crate archive(s), {} in total",
+                    removed.len(), list::format_size(size));
+            }),
+    };
+    result.err().unwrap_or(exitcode::OK)
 }
 
 // Print an error that may occur while parsing arguments.
@@ -151,52 +573,53 @@ fn log_signature() {
 }
 
 
-const CRATES_API_ROOT: &'static str = "https://crates.io/api/v1/crates";
-
-/// Talk to crates.io to get the newest version of given crate
+/// Talk to the registry to get the newest release of given crate
 /// that matches specified version requirements.
-fn get_newest_version(crate_: &Crate) -> Result<Version, Box<Error>> {
-    let versions_url = format!("{}/{}/versions", CRATES_API_ROOT, crate_.name());
-    debug!("Fetching latest matching version of crate `{}` from {}", crate_, versions_url);
-    let response: Json = reqwest::get(&versions_url)?.json()?;
-
-    // TODO: rather that silently skipping over incorrect versions,
-    // report them as malformed response from crates.io
-    let mut versions = response.pointer("/versions").and_then(|vs| vs.as_array()).map(|vs| {
-        vs.iter().filter_map(|v| {
-            v.as_object().and_then(|v| v.get("num")).and_then(|n| n.as_str())
-        })
-        .filter_map(|v| Version::parse(v).ok())
-        .collect::<Vec<_>>()
-    }).ok_or_else(|| format!("malformed response from {}", versions_url))?;
-
-    if versions.is_empty() {
+///
+/// Only the allowed `candidates` are considered, unless the exact version
+/// of the crate has been requested.
+fn get_newest_version(client: Option<&Client>, registry: &Registry, crate_: &Crate,
+                      source: VersionSource,
+                      candidates: Candidates) -> Result<Release, Box<dyn Error>> {
+    debug!("Fetching latest matching version of crate `{}` from registry {} ({})",
+        crate_, registry.name(), source);
+    let releases = index::get_releases(client, registry, crate_.name(), source)?;
+    if releases.is_empty() {
         return Err("no valid versions found".into());
     }
 
+    if let Some(version) = crate_.exact_version() {
+        let release = releases.into_iter().find(|r| &r.vers == version)
+            .ok_or("no matching version found")?;
+        if release.yanked {
+            warn!("Requested version {} of crate `{}` has been yanked", version, crate_.name());
+        }
+        return Ok(release);
+    }
+
     let version_req = crate_.version_requirement();
-    versions.sort_by(|a, b| b.cmp(a));
-    versions.into_iter().find(|v| version_req.matches(v))
-        .map(|v| { info!("Latest version of crate {} is {}", crate_, v); v.to_owned() })
-        .ok_or_else(|| "no matching version found".into())
+    let release = index::resolve(&releases, &version_req, candidates)?;
+    info!("Latest version of crate {} is {}{}",
+        crate_, release.vers, if release.yanked { " (yanked)" } else { "" });
+    Ok(release.to_owned())
 }
 
-/// Download given crate and return it as a vector of gzipped bytes.
-fn download_crate(name: &str, version: &Version) -> Result<Vec<u8>, Box<Error>> {
-    let download_url = format!("{}/{}/{}/download", CRATES_API_ROOT, name, version);
-    debug!("Downloading crate `{}=={}` from {}", name, version, download_url);
-    let mut response = reqwest::get(&download_url)?;
-
-    let content_length: Option<usize> = response.headers().get(CONTENT_LENGTH)
-        .and_then(|ct_len| ct_len.to_str().ok())
-        .and_then(|ct_len| ct_len.parse().ok());
-    trace!("Download size: {}", content_length.map_or("<unknown>".into(), |cl| format!("{} bytes", cl)));
-    let mut bytes = match content_length {
-        Some(cl) => Vec::with_capacity(cl),
-        None => Vec::new(),
-    };
-    response.read_to_end(&mut bytes)?;
+/// Print all versions of given crate that match its version requirement,
+/// newest first.
+fn list_versions(client: Option<&Client>, registry: &Registry, crate_: &Crate,
+                 source: VersionSource) -> Result<(), Box<dyn Error>> {
+    debug!("Listing versions of crate `{}` from registry {} ({})",
+        crate_, registry.name(), source);
+    let releases = index::get_releases(client, registry, crate_.name(), source)?;
 
-    info!("Crate `{}=={}` downloaded successfully", name, version);
-    Ok(bytes)
+    let version_req = crate_.version_requirement();
+    let mut matching: Vec<_> = releases.iter()
+        .filter(|r| index::version_matches(&version_req, &r.vers, true))
+        .collect();
+    if matching.is_empty() {
+        return Err("no matching version found".into());
+    }
+    matching.sort_by(|a, b| b.vers.cmp(&a.vers));
+    list::print_releases(&mut io::stdout(), &matching)?;
+    Ok(())
 }
@@ -0,0 +1,71 @@
+//! Module for machine-readable messages about the downloaded crates,
+//! printed to stdout with `--message-format json`.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use serde_json;
+
+
+/// Message describing a single crate that has been downloaded.
+#[derive(Debug, Serialize)]
+pub struct CrateMessage<'m> {
+    pub name: &'m str,
+    /// Version the crate has been resolved to.
+    pub version: String,
+    /// Version requirement the crate has been requested with.
+    pub requirement: String,
+    /// URL the crate's archive has been downloaded from.
+    pub source: &'m str,
+    /// Size of the archive in bytes.
+    pub size: u64,
+    /// SHA256 checksum of the archive.
+    pub sha256: &'m str,
+    /// Path the archive has been written to, unless it's been extracted.
+    pub output_path: Option<&'m Path>,
+    /// Directory the crate has been extracted to, if it has been.
+    pub extract_dir: Option<&'m Path>,
+    /// Whether the version has been yanked.
+    /// Unknown if the registry hasn't been queried for it (e.g. with --lockfile).
+    pub yanked: Option<bool>,
+}
+
+impl<'m> CrateMessage<'m> {
+    /// Print the message to stdout as a single line of JSON.
+    pub fn print(&self) -> io::Result<()> {
+        let line = serde_json::to_string(self)?;
+        // Lock stdout so that messages from concurrent downloads don't interleave.
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        writeln!(stdout, "{}", line)?;
+        stdout.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use serde_json;
+    use super::CrateMessage;
+
+    #[test]
+    fn crate_message() {
+        let message = CrateMessage{
+            name: "foo",
+            version: "1.0.0".into(),
+            requirement: "^1.0".into(),
+            source: "https://example.com/foo-1.0.0.crate",
+            size: 1024,
+            sha256: "abcd",
+            output_path: None,
+            extract_dir: Some(Path::new("vendor/foo-1.0.0")),
+            yanked: None,
+        };
+        assert_eq!(concat!(
+            r#"{"name":"foo","version":"1.0.0","requirement":"^1.0","#,
+            r#""source":"https://example.com/foo-1.0.0.crate","size":1024,"sha256":"abcd","#,
+            r#""output_path":null,"extract_dir":"vendor/foo-1.0.0","yanked":null}"#,
+        ), serde_json::to_string(&message).unwrap());
+    }
+}
@@ -0,0 +1,252 @@
+//! Module for showing the progress of downloads on stderr.
+//!
+//! There is a single progress line, below the log messages, which is only shown
+//! when stderr is a terminal. With more than one crate to download,
+//! it shows the progress of all of them together.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use list::format_size;
+
+
+/// How often the progress line is redrawn at most.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// Width of the progress bar itself, in characters.
+const BAR_WIDTH: usize = 25;
+/// Terminal sequence that returns to the start of the line and clears it.
+pub const CLEAR_LINE: &str = "\r\x1b[K";
+
+lazy_static! {
+    static ref PROGRESS: Mutex<State> = Mutex::new(State::default());
+}
+
+
+/// Start showing the progress of downloading given number of crates.
+pub fn init(crates: usize) {
+    let mut state = PROGRESS.lock().unwrap();
+    *state = State{enabled: true, crates, started: Some(Instant::now()), ..State::default()};
+}
+
+/// Record that one of the crates has been processed (whether it's been downloaded or not).
+pub fn crate_done() {
+    let mut state = PROGRESS.lock().unwrap();
+    if state.enabled {
+        state.done += 1;
+        state.redraw();
+    }
+}
+
+/// Stop showing the progress, clearing the progress line.
+pub fn finish() {
+    let mut state = PROGRESS.lock().unwrap();
+    state.hide_line();
+    state.enabled = false;
+}
+
+/// Clear the progress line (if it's shown) before something else is written to stderr,
+/// returning whether that needs to be done. It's redrawn with the next update.
+pub fn take_line() -> bool {
+    let mut state = PROGRESS.lock().unwrap();
+    let visible = state.visible;
+    state.visible = false;
+    state.drawn = None;
+    visible
+}
+
+
+/// Tracker of a single download, which updates the progress line.
+/// Once it's dropped, the download no longer counts as being in progress.
+#[derive(Debug)]
+pub struct Tracker {
+    id: usize,
+}
+
+impl Tracker {
+    /// Start tracking the download of given crate, whose size may be known upfront.
+    /// Returns `None` if the progress isn't being shown.
+    pub fn start(name: &str, version: &str, length: Option<u64>) -> Option<Tracker> {
+        let mut state = PROGRESS.lock().unwrap();
+        if !state.enabled {
+            return None;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.downloads.insert(id, Transfer{
+            label: format!("{} {}", name, version),
+            size: 0,
+            length,
+        });
+        state.redraw();
+        Some(Tracker{id})
+    }
+
+    /// Record that given number of bytes has been downloaded.
+    pub fn advance(&self, count: usize) {
+        let mut state = PROGRESS.lock().unwrap();
+        state.bytes += count as u64;
+        if let Some(transfer) = state.downloads.get_mut(&self.id) {
+            transfer.size += count as u64;
+        }
+        let due = state.drawn.is_none_or(|t| t.elapsed() >= REDRAW_INTERVAL);
+        if due {
+            state.redraw();
+        }
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        PROGRESS.lock().unwrap().downloads.remove(&self.id);
+    }
+}
+
+
+/// State of the progress line.
+#[derive(Debug, Default)]
+struct State {
+    enabled: bool,
+    /// Total number of crates to process.
+    crates: usize,
+    /// Number of crates that have been processed.
+    done: usize,
+    /// Downloads in progress.
+    downloads: HashMap<usize, Transfer>,
+    next_id: usize,
+    /// Number of bytes downloaded in total.
+    bytes: u64,
+    started: Option<Instant>,
+    /// When the progress line has been drawn last.
+    drawn: Option<Instant>,
+    /// Whether the progress line is currently on screen.
+    visible: bool,
+}
+
+/// Progress of a single download.
+#[derive(Debug)]
+struct Transfer {
+    label: String,
+    /// Number of bytes downloaded so far.
+    size: u64,
+    /// Total size of the download, if known.
+    length: Option<u64>,
+}
+
+impl State {
+    fn redraw(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        let elapsed = self.started.map_or(Duration::from_secs(0), |s| s.elapsed());
+        let line = format!("{}{}", CLEAR_LINE, self.render(elapsed));
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        if stderr.write_all(line.as_bytes()).and_then(|_| stderr.flush()).is_ok() {
+            self.visible = true;
+        }
+        self.drawn = Some(Instant::now());
+    }
+
+    fn hide_line(&mut self) {
+        if self.visible {
+            let _ = io::stderr().write_all(CLEAR_LINE.as_bytes());
+            self.visible = false;
+        }
+    }
+
+    /// Format the progress line, given the time since the downloads have started.
+    fn render(&self, elapsed: Duration) -> String {
+        let secs = elapsed.as_secs_f64();
+        let rate = if secs > 0.0 { (self.bytes as f64 / secs) as u64 } else { 0 };
+
+        // With a single crate, the progress is that of its download (if its size is known),
+        // otherwise it's the fraction of processed crates, including partial downloads.
+        let single = if self.crates == 1 { self.downloads.values().next() } else { None };
+        let (label, fraction, size) = match single {
+            Some(transfer) => (
+                transfer.label.clone(),
+                transfer.length.map(|l| if l > 0 { transfer.size as f64 / l as f64 } else { 1.0 }),
+                match transfer.length {
+                    Some(length) => format!("{} / {}", format_size(transfer.size), format_size(length)),
+                    None => format_size(transfer.size),
+                },
+            ),
+            None => {
+                let partial: f64 = self.downloads.values()
+                    .filter_map(|t| t.length.filter(|&l| l > 0).map(|l| t.size as f64 / l as f64))
+                    .sum();
+                let fraction = (self.done as f64 + partial) / self.crates.max(1) as f64;
+                (format!("{}/{} crates", self.done, self.crates), Some(fraction), format_size(self.bytes))
+            }
+        };
+
+        let mut line = label;
+        if let Some(fraction) = fraction.map(|f| f.clamp(0.0, 1.0)) {
+            let filled = (fraction * BAR_WIDTH as f64) as usize;
+            let bar = match filled {
+                0 => " ".repeat(BAR_WIDTH),
+                f if f >= BAR_WIDTH => "=".repeat(BAR_WIDTH),
+                f => format!("{}>{}", "=".repeat(f - 1), " ".repeat(BAR_WIDTH - f)),
+            };
+            line.push_str(&format!(" [{}] {:>3}%", bar, (fraction * 100.0) as u32));
+            line.push_str(&format!("  {}  {}/s", size, format_size(rate)));
+            if fraction > 0.0 && fraction < 1.0 {
+                let eta = secs * (1.0 - fraction) / fraction;
+                line.push_str(&format!("  ETA {}", format_eta(eta)));
+            }
+        } else {
+            line.push_str(&format!("  {}  {}/s", size, format_size(rate)));
+        }
+        line
+    }
+}
+
+/// Format the estimated remaining time, given in seconds.
+fn format_eta(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 3600 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{format_eta, State, Transfer};
+
+    #[test]
+    fn single_download() {
+        let mut state = State{crates: 1, bytes: 1024, ..State::default()};
+        state.downloads.insert(0, Transfer{label: "foo 1.0.0".into(), size: 1024, length: Some(4096)});
+        assert_eq!(
+            "foo 1.0.0 [=====>                   ]  25%  1.0 KiB / 4.0 KiB  512 B/s  ETA 6s",
+            state.render(Duration::from_secs(2)));
+
+        state.downloads.get_mut(&0).unwrap().length = None;
+        assert_eq!("foo 1.0.0  1.0 KiB  512 B/s", state.render(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn multiple_downloads() {
+        let mut state = State{crates: 4, done: 1, bytes: 3072, ..State::default()};
+        state.downloads.insert(0, Transfer{label: "foo 1.0.0".into(), size: 1024, length: Some(2048)});
+        state.downloads.insert(1, Transfer{label: "bar 1.0.0".into(), size: 2048, length: None});
+        assert_eq!(
+            "1/4 crates [========>                ]  37%  3.0 KiB  1.0 KiB/s  ETA 5s",
+            state.render(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn eta() {
+        assert_eq!("5s", format_eta(4.6));
+        assert_eq!("2m05s", format_eta(125.0));
+        assert_eq!("1h01m", format_eta(3690.0));
+    }
+}
@@ -0,0 +1,583 @@
+//! Module describing the crate registries we can download from.
+//!
+//! A registry is identified by its index, whose `config.json` tells us
+//! where crate archives can be downloaded from and where its web API lives
+//! (https://doc.rust-lang.org/cargo/reference/registry-index.html#index-configuration).
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use hex;
+use reqwest;
+use semver::Version;
+use serde_json::{self, Value as Json};
+use sha2::{Digest, Sha256};
+
+use cargo_index;
+use config::{cargo_home, Config};
+use credentials::{self, CredentialError};
+use http::{Client, Token};
+
+
+/// Name that Cargo uses to refer to crates.io.
+pub const CRATES_IO: &str = "crates-io";
+
+const CRATES_IO_INDEX: &str = "sparse+https://index.crates.io/";
+const CRATES_IO_GIT_INDEX: &str = "https://github.com/rust-lang/crates.io-index";
+const CRATES_IO_DL: &str = "https://crates.io/api/v1/crates";
+const CRATES_IO_API: &str = "https://crates.io";
+
+/// Markers that can appear in the `dl` template of a registry.
+const DL_MARKERS: &[&str] = &[
+    "{crate}", "{version}", "{prefix}", "{lowerprefix}", "{sha256-checksum}",
+];
+
+
+/// A crate registry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Registry {
+    /// Name of the registry, as used in Cargo config.
+    name: String,
+    /// URL of the registry index.
+    index: String,
+    /// Template of the crate download URLs.
+    dl: String,
+    /// Root URL of the registry's web API, if it has one.
+    api: Option<String>,
+    /// Token to authenticate with, for private registries that require it.
+    token: Option<Token>,
+}
+
+impl Registry {
+    /// The default crates.io registry.
+    pub fn crates_io() -> Self {
+        Registry{
+            name: CRATES_IO.into(),
+            index: CRATES_IO_INDEX.into(),
+            dl: CRATES_IO_DL.into(),
+            api: Some(CRATES_IO_API.into()),
+            token: None,
+        }
+    }
+
+    /// Registry of given name, as defined in Cargo config
+    /// (i.e. `[registries.$NAME]` sections).
+    ///
+    /// If the registry requires authentication, its token is either the one given,
+    /// or the one from Cargo's credential providers (by default, `registries.$NAME.token`
+    /// from Cargo's credentials, which can also be set with `CARGO_REGISTRIES_$NAME_TOKEN`).
+    /// The providers aren't asked otherwise, nor in offline mode.
+    pub fn from_config(client: Option<&Client>, name: &str, config: &Config,
+                       token: Option<String>) -> Result<Self, RegistryError> {
+        if name == CRATES_IO {
+            return Ok(Registry::crates_io());
+        }
+        let index = config.get_str(&["registries", name, "index"])
+            .ok_or_else(|| RegistryError::Unknown(name.to_owned()))?;
+        debug!("Index URL of registry `{}` is {}", name, index);
+        Registry::load(client, name, &index, || {
+            let token = match token {
+                Some(token) => Some(token),
+                None if client.is_some() => credentials::get_token(config, name, &index)?,
+                None => None,
+            };
+            match token {
+                Some(token) => {
+                    debug!("Using a token for registry `{}`", name);
+                    let token = Token::new(&token, &index)
+                        .ok_or_else(|| RegistryError::InvalidToken(name.to_owned()))?;
+                    Ok(Some(token))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// Registry with given name and index URL.
+    /// This reads the `config.json` file from the index.
+    #[inline]
+    pub fn from_index(client: Option<&Client>, name: &str, index: &str) -> Result<Self, RegistryError> {
+        Registry::load(client, name, index, || Ok(None))
+    }
+
+    /// Load the registry with given name and index URL,
+    /// calling `get_token` if (and only if) it requires authentication.
+    fn load<F>(client: Option<&Client>, name: &str, index: &str,
+               get_token: F) -> Result<Self, RegistryError>
+        where F: FnOnce() -> Result<Option<Token>, RegistryError>
+    {
+        let index = if index.ends_with('/') { index.to_owned() } else { format!("{}/", index) };
+        // Like Cargo, only authenticate to registries that require it.
+        // Their config.json may not be readable anonymously (401 Unauthorized),
+        // otherwise it declares whether they do with the `auth-required` key.
+        let mut get_token = Some(get_token);
+        let (config_json, token) = match read_index_file(client, None, &index, "config.json") {
+            Err(RegistryError::Status(ref url, 401)) => {
+                debug!("Registry `{}` requires authentication to read {}", name, url);
+                let token = get_token.take().unwrap()()?
+                    .ok_or_else(|| RegistryError::Unauthorized(name.to_owned()))?;
+                (read_index_file(client, Some(&token), &index, "config.json")?, Some(token))
+            }
+            result => (result?, None),
+        };
+        let config_json = config_json
+            .ok_or_else(|| RegistryError::Config("file not found".into()))?;
+        let config: Json = serde_json::from_slice(&config_json)
+            .map_err(|e| RegistryError::Config(e.to_string()))?;
+
+        let dl = config.get("dl").and_then(|dl| dl.as_str())
+            .ok_or_else(|| RegistryError::Config("missing `dl` key".into()))?;
+        let api = config.get("api").and_then(|api| api.as_str());
+        trace!("Registry `{}` has dl={} and api={}", name, dl, api.unwrap_or("<none>"));
+        let auth_required = token.is_some()
+            || config.get("auth-required").and_then(|a| a.as_bool()).unwrap_or(false);
+        let token = match (token, get_token) {
+            (Some(token), _) => Some(token),
+            (None, Some(get_token)) if auth_required => {
+                let token = get_token()?;
+                if token.is_none() && client.is_some() {
+                    warn!("Registry `{}` requires authentication, but there is no token for it", name);
+                }
+                token
+            }
+            (None, _) => None,
+        };
+        Ok(Registry{
+            name: name.to_owned(),
+            index,
+            dl: dl.to_owned(),
+            api: api.map(|a| a.trim_end_matches('/').to_owned()),
+            token,
+        })
+    }
+
+    /// Registry with given index URL, as found in the `source` of Cargo.lock packages.
+    /// Unless it's crates.io, the registry is named after its index.
+    pub fn from_source(client: Option<&Client>, index: &str) -> Result<Self, RegistryError> {
+        let crates_io = Registry::crates_io();
+        if crates_io.has_index(index) || index == CRATES_IO_GIT_INDEX {
+            return Ok(crates_io);
+        }
+        Registry::from_index(client, index, index)
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Token to authenticate requests to the registry with, if any.
+    #[inline]
+    pub fn token(&self) -> Option<&Token> {
+        self.token.as_ref()
+    }
+
+    /// Identifier of the registry that can be used as a file name.
+    ///
+    /// Like the directories in Cargo's own registry cache, it combines
+    /// the registry's name with a hash of its index URL.
+    pub fn id(&self) -> String {
+        let name: String = self.name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let hash = Sha256::digest(self.index.trim_end_matches('/').as_bytes());
+        format!("{}-{}", name, hex::encode(&hash[..8]))
+    }
+
+    /// Host names that Cargo's own directories for this registry
+    /// (e.g. in `$CARGO_HOME/registry/cache`) are named after,
+    /// followed by a hash of the index URL that we don't reproduce.
+    pub fn cargo_hosts(&self) -> Vec<&str> {
+        let mut hosts = cargo_hosts(&self.index);
+        if self.name == CRATES_IO && !hosts.contains(&"github.com") {
+            hosts.push("github.com");
+        }
+        hosts
+    }
+
+    /// Whether the registry's index is at given URL.
+    #[inline]
+    pub fn has_index(&self, index: &str) -> bool {
+        self.index.trim_end_matches('/') == index.trim_end_matches('/')
+    }
+
+    /// Read the contents of a file at given path relative to the index root.
+    /// Returns `None` if the file doesn't exist.
+    #[inline]
+    pub fn read_index_file(&self, client: Option<&Client>,
+                           path: &str) -> Result<Option<Vec<u8>>, RegistryError> {
+        read_index_file(client, self.token.as_ref(), &self.index, path)
+    }
+
+    /// Whether the registry has a web API.
+    #[inline]
+    pub fn has_api(&self) -> bool {
+        self.api.is_some()
+    }
+
+    /// Whether downloading a crate from this registry requires knowing its checksum.
+    #[inline]
+    pub fn needs_checksum(&self) -> bool {
+        self.dl.contains("{sha256-checksum}")
+    }
+
+    /// URL of the web API endpoint that lists all versions of given crate.
+    pub fn versions_url(&self, crate_: &str) -> Result<String, RegistryError> {
+        let api = self.api.as_ref().ok_or_else(|| RegistryError::NoApi(self.name.clone()))?;
+        Ok(format!("{}/api/v1/crates/{}/versions", api, crate_))
+    }
+
+    /// URL to download the archive of given crate version from.
+    pub fn download_url(&self, crate_: &str, version: &Version,
+                        checksum: Option<&str>) -> Result<String, RegistryError> {
+        // Names from lockfiles haven't been validated yet.
+        if !is_valid_crate_name(crate_) {
+            return Err(RegistryError::CrateName(crate_.to_owned()));
+        }
+        if !DL_MARKERS.iter().any(|m| self.dl.contains(m)) {
+            return Ok(format!("{}/{}/{}/download", self.dl.trim_end_matches('/'), crate_, version));
+        }
+        let mut url = self.dl
+            .replace("{crate}", crate_)
+            .replace("{version}", &version.to_string())
+            .replace("{prefix}", &index_prefix(crate_))
+            .replace("{lowerprefix}", &index_prefix(&crate_.to_lowercase()));
+        if self.needs_checksum() {
+            let checksum = checksum.ok_or(RegistryError::ChecksumRequired)?;
+            url = url.replace("{sha256-checksum}", checksum);
+        }
+        Ok(url)
+    }
+}
+
+impl fmt::Display for Registry {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} ({})", self.name, self.index)
+    }
+}
+
+
+/// Whether the name is a valid crate name: non-empty,
+/// with only ASCII letters, digits, hyphens and underscores.
+pub fn is_valid_crate_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Directory prefix of given crate within a registry index,
+/// e.g. "se/rd" for "serde" or "3/a" for "abc".
+///
+/// The crate name must be valid (see `is_valid_crate_name`).
+pub fn index_prefix(crate_: &str) -> String {
+    debug_assert!(is_valid_crate_name(crate_), "invalid crate name `{}`", crate_);
+    match crate_.len() {
+        1 => "1".into(),
+        2 => "2".into(),
+        3 => format!("3/{}", &crate_[..1]),
+        _ => format!("{}/{}", &crate_[..2], &crate_[2..4]),
+    }
+}
+
+/// Host names of Cargo's own directories for the registry with given index.
+fn cargo_hosts(index: &str) -> Vec<&str> {
+    let url = index.trim_start_matches("sparse+").trim_start_matches("registry+");
+    let host = url.split("://").nth(1).unwrap_or(url)
+        .split(['/', ':']).next().unwrap_or("");
+    let mut hosts = vec![host];
+    if index.trim_end_matches('/') == CRATES_IO_INDEX.trim_end_matches('/') {
+        // Cargo used to get crates.io packages from its git index on GitHub.
+        hosts.push("github.com");
+    }
+    hosts
+}
+
+/// Read the contents of a file at given path relative to the index root,
+/// or `None` if the file doesn't exist.
+///
+/// Without a `client` (i.e. in offline mode), remote indices are read
+/// from Cargo's local copies of them instead. So are git indices,
+/// which can't be fetched over HTTP like sparse ones.
+fn read_index_file(client: Option<&Client>, token: Option<&Token>, index: &str,
+                   path: &str) -> Result<Option<Vec<u8>>, RegistryError> {
+    if let Some(dir) = index.strip_prefix("file://") {
+        let file = PathBuf::from(dir).join(path);
+        trace!("Reading index file {}", file.display());
+        return match fs::read(&file) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(RegistryError::Io(e)),
+        };
+    }
+    let client = match client {
+        Some(client) => client,
+        None => {
+            let url = format!("{}/{}", index.trim_start_matches("sparse+").trim_end_matches('/'), path);
+            return read_cargo_copy(index, path).map(Some).ok_or(RegistryError::Offline(url));
+        }
+    };
+    let root = match index.strip_prefix("sparse+") {
+        Some(root) => root,
+        None => {
+            trace!("Reading {} from Cargo's local copy of git index {}", path, index);
+            return read_cargo_copy(index, path).map(Some)
+                .ok_or_else(|| RegistryError::GitIndex(index.to_owned()));
+        }
+    };
+
+    let url = format!("{}{}", root, path);
+    trace!("Fetching index file from {}", url);
+    let mut response = client.get(&url, token).map_err(RegistryError::Http)?;
+    // Sparse registries may also use 410 Gone or 451 Unavailable For Legal Reasons
+    // to indicate that a crate doesn't exist.
+    match response.status().as_u16() {
+        404 | 410 | 451 => return Ok(None),
+        s if !response.status().is_success() => return Err(RegistryError::Status(url, s)),
+        _ => {}
+    }
+    let mut bytes = vec![];
+    response.copy_to(&mut bytes).map_err(RegistryError::Http)?;
+    Ok(Some(bytes))
+}
+
+/// Read a file from Cargo's local copy of given index, if it's there.
+fn read_cargo_copy(index: &str, path: &str) -> Option<Vec<u8>> {
+    let cargo_index_dir = cargo_home()?.join("registry").join("index");
+    cargo_index::read_file(&cargo_index_dir, &cargo_hosts(index), path)
+}
+
+
+/// Error that can occur when talking to a registry.
+#[derive(Debug)]
+pub enum RegistryError {
+    /// Registry of given name isn't defined in Cargo config.
+    Unknown(String),
+    /// Registry's index is a git repository, whose file isn't in Cargo's local copy of it.
+    GitIndex(String),
+    /// Registry of given name has no web API.
+    NoApi(String),
+    /// Registry's `config.json` is invalid.
+    Config(String),
+    /// Token of given registry isn't valid in an HTTP header.
+    InvalidToken(String),
+    /// Registry of given name requires authentication, but there is no token for it.
+    Unauthorized(String),
+    /// Error while getting the token from a credential provider.
+    Credentials(CredentialError),
+    /// Registry requires the checksum of a crate to download it.
+    ChecksumRequired,
+    /// Crate name isn't valid, so it can't be in the registry.
+    CrateName(String),
+    /// HTTP request returned an error status code.
+    Status(String, u16),
+    /// Error while making an HTTP request.
+    Http(reqwest::Error),
+    /// I/O error while reading a local index.
+    Io(io::Error),
+    /// Request to given URL would be needed in offline mode,
+    /// and Cargo doesn't have a local copy of the file either.
+    Offline(String),
+}
+impl From<CredentialError> for RegistryError {
+    fn from(input: CredentialError) -> Self {
+        RegistryError::Credentials(input)
+    }
+}
+impl Error for RegistryError {
+    fn description(&self) -> &str { "registry error" }
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            RegistryError::Credentials(ref e) => Some(e),
+            RegistryError::Http(ref e) => Some(e),
+            RegistryError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for RegistryError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RegistryError::Unknown(ref n) =>
+                write!(fmt, "registry `{}` not found in Cargo config", n),
+            RegistryError::GitIndex(ref i) => write!(fmt, concat!(
+                "git registry index {} can only be read from Cargo's local copy, ",
+                "which doesn't have the file (let Cargo fetch the index first, ",
+                "e.g. with `cargo update`, or use a sparse+ URL)"), i),
+            RegistryError::NoApi(ref n) => write!(fmt, "registry `{}` has no web API", n),
+            RegistryError::Config(ref e) => write!(fmt, "invalid registry config.json: {}", e),
+            RegistryError::InvalidToken(ref n) => write!(fmt, "invalid token for registry `{}`", n),
+            RegistryError::Unauthorized(ref n) =>
+                write!(fmt, "registry `{}` requires authentication, but there is no token for it", n),
+            RegistryError::Credentials(ref e) => write!(fmt, "cannot get registry token: {}", e),
+            RegistryError::ChecksumRequired =>
+                write!(fmt, "registry requires crate checksum to download it"),
+            RegistryError::CrateName(ref n) => write!(fmt, "invalid crate name `{}`", n),
+            RegistryError::Status(ref url, s) => write!(fmt, "HTTP status {} from {}", s, url),
+            RegistryError::Http(ref e) => write!(fmt, "HTTP error: {}", e),
+            RegistryError::Io(ref e) => write!(fmt, "I/O error: {}", e),
+            RegistryError::Offline(ref url) =>
+                write!(fmt, "cannot fetch {} in offline mode (not in Cargo's local index)", url),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use semver::Version;
+    use test_server::{client, Response, TestServer};
+    use config::Config;
+    use super::{index_prefix, is_valid_crate_name, Registry, RegistryError};
+
+    #[test]
+    fn prefixes() {
+        assert_eq!("1", index_prefix("a"));
+        assert_eq!("2", index_prefix("ab"));
+        assert_eq!("3/a", index_prefix("abc"));
+        assert_eq!("se/rd", index_prefix("serde"));
+        assert_eq!("Se/rD", index_prefix("SerDe"));
+    }
+
+    #[test]
+    fn crate_names() {
+        assert!(is_valid_crate_name("serde_json"));
+        assert!(is_valid_crate_name("cargo-download"));
+        assert!(!is_valid_crate_name(""));
+        assert!(!is_valid_crate_name("aéb"));
+        assert!(!is_valid_crate_name("foo/bar"));
+    }
+
+    #[test]
+    fn crates_io_download_url() {
+        let registry = Registry::crates_io();
+        let url = registry.download_url("foo", &Version::parse("1.2.3").unwrap(), None).unwrap();
+        assert_eq!("https://crates.io/api/v1/crates/foo/1.2.3/download", url);
+    }
+
+    #[test]
+    fn templated_download_url() {
+        let registry = Registry{
+            name: "test".into(),
+            index: "sparse+http://localhost/".into(),
+            dl: "http://localhost/{lowerprefix}/{crate}/{version}/{sha256-checksum}".into(),
+            api: None,
+            token: None,
+        };
+        let version = Version::parse("0.1.0").unwrap();
+        assert!(registry.download_url("FooBar", &version, None).is_err());
+        let url = registry.download_url("FooBar", &version, Some("abc")).unwrap();
+        assert_eq!("http://localhost/fo/ob/FooBar/0.1.0/abc", url);
+        assert!(registry.versions_url("FooBar").is_err());
+    }
+
+    #[test]
+    fn config_json_from_sparse_index() {
+        let server = TestServer::serve(vec![
+            Response::ok(r#"{"dl": "http://example.com/dl/{crate}-{version}.crate", "api": "http://example.com/"}"#),
+        ]);
+        let index = format!("sparse+{}/index", server.url());
+        let client = client(0);
+        let registry = Registry::from_index(Some(&client), "internal", &index).unwrap();
+        assert_eq!("internal", registry.name());
+        assert_eq!(format!("{}/", index), registry.index);
+
+        let version = Version::parse("2.0.0").unwrap();
+        assert_eq!("http://example.com/dl/foo-2.0.0.crate",
+            registry.download_url("foo", &version, None).unwrap());
+        assert_eq!("http://example.com/api/v1/crates/foo/versions",
+            registry.versions_url("foo").unwrap());
+        assert_eq!(vec!["/index/config.json".to_owned()], server.requested_paths());
+    }
+
+    #[test]
+    fn tokens_sent_if_required() {
+        for &(config_json, sent) in &[
+            (r#"{"dl": "$SERVER/dl", "auth-required": true}"#, true),
+            (r#"{"dl": "$SERVER/dl"}"#, false),
+        ] {
+            let server = TestServer::serve(vec![Response::ok(config_json), Response::ok("")]);
+            let config = Config::from_toml(&format!(
+                "[registries.private]\nindex = \"sparse+{}/\"\n", server.url()));
+            let client = client(0);
+            let registry = Registry::from_config(
+                Some(&client), "private", &config, Some("secret".into())).unwrap();
+            assert_eq!(sent, registry.token().is_some());
+            registry.read_index_file(Some(&client), "3/f/foo").unwrap();
+
+            // config.json itself is fetched anonymously, like Cargo does.
+            let requests = server.requests();
+            assert_eq!(None, requests[0].header("Authorization"));
+            assert_eq!(if sent { Some("secret") } else { None }, requests[1].header("Authorization"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn credential_providers_run_if_required() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+        use tempfile;
+
+        let dir = tempfile::tempdir().unwrap();
+        let provider = dir.path().join("provider.sh");
+        let marker = dir.path().join("ran");
+        fs::write(&provider, format!("#!/bin/sh\ntouch {}\necho secret\n", marker.display())).unwrap();
+        fs::set_permissions(&provider, fs::Permissions::from_mode(0o755)).unwrap();
+        let client = client(0);
+
+        for &(config_json, required) in &[
+            (r#"{"dl": "$SERVER/dl"}"#, false),
+            (r#"{"dl": "$SERVER/dl", "auth-required": true}"#, true),
+        ] {
+            let server = TestServer::serve(vec![Response::ok(config_json)]);
+            let config = Config::from_toml(&format!(concat!(
+                "[registries.corp]\nindex = \"sparse+{}/\"\n",
+                "credential-provider = [\"cargo:token-from-stdout\", \"{}\"]\n",
+            ), server.url(), provider.display()));
+            let registry = Registry::from_config(Some(&client), "corp", &config, None).unwrap();
+            assert_eq!(required, registry.token().is_some());
+            assert_eq!(required, marker.exists());
+        }
+    }
+
+    #[test]
+    fn unauthorized_config_json() {
+        let server = TestServer::serve(vec![
+            Response::new(401, ""),
+            Response::ok(r#"{"dl": "$SERVER/dl"}"#),
+            Response::new(401, ""),
+        ]);
+        let config = Config::from_toml(&format!(
+            "[registries.private]\nindex = \"sparse+{}/\"\n", server.url()));
+        let client = client(0);
+        let registry = Registry::from_config(
+            Some(&client), "private", &config, Some("secret".into())).unwrap();
+        assert!(registry.token().is_some());
+        assert_eq!(Some("secret"), server.requests()[1].header("Authorization"));
+
+        match Registry::from_config(Some(&client), "private", &config, None) {
+            Err(RegistryError::Unauthorized(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn lockfile_sources() {
+        let client = client(0);
+        for index in &["https://github.com/rust-lang/crates.io-index", "sparse+https://index.crates.io/"] {
+            assert_eq!(Registry::crates_io(), Registry::from_source(Some(&client), index).unwrap());
+        }
+        let server = TestServer::serve(vec![Response::ok(r#"{"dl": "http://example.com/dl"}"#)]);
+        let index = format!("sparse+{}/", server.url());
+        let registry = Registry::from_source(Some(&client), &index).unwrap();
+        assert_eq!(index, registry.name());
+        assert!(registry.has_index(index.trim_end_matches('/')));
+    }
+
+    #[test]
+    fn git_index_unsupported() {
+        let client = client(0);
+        assert!(Registry::from_index(Some(&client), "git", "https://example.com/index.git").is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Minimal HTTP server for tests, standing in for a crate registry.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use http::{Client, HttpConfig};
+use registry::Registry;
+
+
+/// Canned HTTP response to serve.
+#[derive(Clone, Debug)]
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    delay: Option<Duration>,
+}
+
+impl Response {
+    pub fn new<B: Into<Vec<u8>>>(status: u16, body: B) -> Self {
+        Response{status, headers: vec![], body: body.into(), delay: None}
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Send the body one byte at a time, pausing for given time before each.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    #[inline]
+    pub fn ok<B: Into<Vec<u8>>>(body: B) -> Self {
+        Response::new(200, body)
+    }
+}
+
+
+/// HTTP request received by the server.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Value of the request header with given name, if any.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+
+/// HTTP server that serves given responses, one per connection, in order.
+///
+/// Any occurrence of `$SERVER` in response bodies is replaced with the server's root URL.
+pub struct TestServer {
+    port: u16,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    pub fn serve(responses: Vec<Response>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let url = format!("http://127.0.0.1:{}", port);
+        let responses: Vec<_> = responses.into_iter().map(|mut r| {
+            r.body = String::from_utf8_lossy(&r.body).replace("$SERVER", &url).into_bytes();
+            r
+        }).collect();
+
+        let reqs = requests.clone();
+        thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or("").to_owned();
+                let mut headers = vec![];
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+                    }
+                }
+                reqs.lock().unwrap().push(Request{path, headers});
+
+                let mut stream = reader.into_inner();
+                let mut head = format!("HTTP/1.1 {} Test\r\nConnection: close\r\n", response.status);
+                if !response.headers.iter().any(|(n, _)| n.eq_ignore_ascii_case("Content-Length")) {
+                    head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+                }
+                for (name, value) in &response.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                head.push_str("\r\n");
+                let _ = stream.write_all(head.as_bytes());
+                match response.delay {
+                    Some(delay) => for byte in &response.body {
+                        thread::sleep(delay);
+                        if stream.write_all(&[*byte]).and_then(|_| stream.flush()).is_err() {
+                            break;
+                        }
+                    },
+                    None => { let _ = stream.write_all(&response.body); }
+                }
+            }
+        });
+
+        TestServer{port, requests}
+    }
+
+    /// Root URL of the server, without the trailing slash.
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// Requests received so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Paths of the requests received so far.
+    pub fn requested_paths(&self) -> Vec<String> {
+        self.requests().into_iter().map(|r| r.path).collect()
+    }
+
+    /// Client making given number of retries, and a registry with its sparse index
+    /// on this server. The first response is served as its config.json.
+    pub fn registry(&self, retries: u32) -> (Client, Registry) {
+        let client = client(retries);
+        let registry = Registry::from_index(
+            Some(&client), "test", &format!("sparse+{}/", self.url())).unwrap();
+        (client, registry)
+    }
+}
+
+
+/// HTTP client with default settings, making given number of retries
+/// without waiting long between them.
+pub fn client(retries: u32) -> Client {
+    Client::new(&HttpConfig::default(), retries).unwrap()
+        .with_initial_backoff(Duration::from_millis(1))
+}